@@ -0,0 +1,58 @@
+use super::error::{AgentError, AgentResult};
+use crate::language_models::{completions::usage::UsageTracker, pricing::PricingTable};
+use serde::{Deserialize, Serialize};
+
+/// Caps on how much an `Agent` may spend. Once either cap is reached the agent refuses to make
+/// any more completions until its usage is reset or the budget is raised
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Budget {
+    /// Maximum prompt + completion tokens
+    pub max_tokens: Option<u32>,
+    /// Maximum estimated cost in US dollars
+    pub max_cost: Option<f64>,
+    /// Prices used to estimate cost, completions & embeddings of models missing from the table cost
+    /// nothing
+    pub pricing: PricingTable,
+}
+
+impl Budget {
+    pub fn tokens(max_tokens: u32) -> Self {
+        Self::default().with_max_tokens(max_tokens)
+    }
+
+    pub fn dollars(max_cost: f64) -> Self {
+        Self::default().with_max_cost(max_cost)
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_max_cost(mut self, max_cost: f64) -> Self {
+        self.max_cost = Some(max_cost);
+        self
+    }
+
+    pub fn with_pricing(mut self, pricing: PricingTable) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    /// Errors if `usage` has reached either cap
+    pub(crate) fn check(&self, usage: &UsageTracker) -> AgentResult<()> {
+        if let Some(limit) = self.max_tokens {
+            let used = usage.total().total_tokens();
+            if used >= limit {
+                return Err(AgentError::TokenBudgetExceeded { used, limit });
+            }
+        }
+        if let Some(limit) = self.max_cost {
+            let spent = usage.cost();
+            if spent >= limit {
+                return Err(AgentError::CostBudgetExceeded { spent, limit });
+            }
+        }
+        Ok(())
+    }
+}
@@ -7,6 +7,16 @@ pub enum AgentError {
     #[error(transparent)]
     Undefined(#[from] anyhow::Error),
     CompletionError(#[from] CompletionError),
+    /// The agent's `Budget` token cap has been reached
+    TokenBudgetExceeded {
+        used: u32,
+        limit: u32,
+    },
+    /// The agent's `Budget` cost cap, in US dollars, has been reached
+    CostBudgetExceeded {
+        spent: f64,
+        limit: f64,
+    },
 }
 
 impl Debug for AgentError {
@@ -20,7 +30,29 @@ impl Display for AgentError {
         let display = match self {
             Self::Undefined(err) => err.to_string(),
             Self::CompletionError(err) => err.to_string(),
+            Self::TokenBudgetExceeded { used, limit } => {
+                format!("Token budget exceeded: used {} of {} tokens", used, limit)
+            }
+            Self::CostBudgetExceeded { spent, limit } => {
+                format!("Cost budget exceeded: spent ${:.4} of ${:.4}", spent, limit)
+            }
         };
         write!(f, "{}", display)
     }
 }
+
+impl AgentError {
+    /// The underlying completion error, if the failure came from the model
+    pub fn completion_error(&self) -> Option<&CompletionError> {
+        match self {
+            Self::CompletionError(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Whether making the same completion again may succeed, budget errors never are
+    pub fn is_retryable(&self) -> bool {
+        self.completion_error()
+            .is_some_and(CompletionError::is_retryable)
+    }
+}
@@ -1,11 +1,25 @@
+pub mod budget;
 pub mod error;
 pub mod memory;
-use crate::language_models::completions::{
-    functions::Function, streaming::ProviderStreamHandler, CompletionModel,
+use crate::language_models::{
+    completions::{
+        fallback::AnsweredBy,
+        functions::{Function, ToolCall, ToolChoice},
+        inference::{CompletionChoice, IoCompletion},
+        streaming::ProviderStreamHandler,
+        usage::{TokenUsage, UsageTracker},
+        CompletionModel, CompletionProvider,
+    },
+    pricing::PricingTable,
+    secret::SecretString,
+    semantic_cache::SemanticCache,
 };
+use anyhow::anyhow;
+use budget::Budget;
 pub use error::AgentError;
-use memory::MessageStack;
-use std::fmt::Debug;
+use memory::{MessageRole, MessageStack};
+use std::{fmt::Debug, time::Duration};
+use tracing::warn;
 
 use error::AgentResult;
 
@@ -13,6 +27,18 @@ use error::AgentResult;
 pub struct Agent {
     pub cache: MessageStack,
     pub completion_model: CompletionModel,
+    /// Caps checked before every completion, `None` means the agent may spend without limit
+    #[serde(default)]
+    pub budget: Option<Budget>,
+    #[serde(default)]
+    pub(crate) usage: UsageTracker,
+    #[serde(default)]
+    answered_by: Option<AnsweredBy>,
+    #[serde(skip)]
+    bypass_cache: bool,
+    /// Answers io completions with stored ones for similar user messages, not serialized
+    #[serde(skip)]
+    pub semantic_cache: Option<SemanticCache>,
 }
 
 impl Agent {
@@ -25,20 +51,200 @@ impl Agent {
         Agent {
             cache,
             completion_model,
+            budget: None,
+            usage: UsageTracker::default(),
+            answered_by: None,
+            bypass_cache: false,
+            semantic_cache: None,
         }
     }
 
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    pub fn with_semantic_cache(mut self, semantic_cache: SemanticCache) -> Self {
+        self.semantic_cache = Some(semantic_cache);
+        self
+    }
+
+    /// Api keys are not serialized, give them back to a deserialized agent's completion model &
+    /// fallbacks, for example `agent.supply_api_keys(|_| Some(key.clone()))`
+    pub fn supply_api_keys(&mut self, keys: impl Fn(&CompletionProvider) -> Option<SecretString>) {
+        self.completion_model.supply_api_keys(keys);
+    }
+
+    /// Gives the completion model, its fallbacks & the semantic cache's embedding model `client`,
+    /// for example after deserializing an agent that should use a client built from a
+    /// `TransportConfig`
+    pub fn set_client(&mut self, client: reqwest::Client) {
+        if let Some(semantic_cache) = self.semantic_cache.as_mut() {
+            semantic_cache.set_client(client.clone());
+        }
+        self.completion_model.set_client(client);
+    }
+
+    /// Token usage & estimated cost of every completion this agent has made
+    pub fn usage(&self) -> &UsageTracker {
+        &self.usage
+    }
+
+    pub fn reset_usage(&mut self) {
+        self.usage.reset();
+    }
+
+    /// The next completion skips reading the model's response cache, its fresh response still
+    /// replaces the cached one. For example `agent.bypass_cache().io_completion().await`
+    pub fn bypass_cache(&mut self) -> &mut Self {
+        self.bypass_cache = true;
+        self
+    }
+
+    /// Whether this completion may read the cache, resetting `bypass_cache`
+    fn read_cache(&mut self) -> bool {
+        !std::mem::take(&mut self.bypass_cache)
+    }
+
+    /// The model that answered the most recent completion, which is only ever one of the completion
+    /// model's fallbacks if the models before it failed
+    pub fn answered_by(&self) -> Option<&AnsweredBy> {
+        self.answered_by.as_ref()
+    }
+
+    /// Errors if the agent's budget has been spent
+    fn check_budget(&self) -> AgentResult<()> {
+        match &self.budget {
+            Some(budget) => budget.check(&self.usage),
+            None => Ok(()),
+        }
+    }
+
+    /// Costs are estimated with the budget's pricing, or the default pricing without a budget
+    fn cost(&self, model: &str, usage: Option<TokenUsage>) -> Option<f64> {
+        usage.and_then(|usage| match &self.budget {
+            Some(budget) => budget.pricing.cost(model, usage),
+            None => PricingTable::default().cost(model, usage),
+        })
+    }
+
+    /// Usage is priced as the model that answered
+    pub(crate) fn record_usage(&mut self, usage: Option<TokenUsage>) {
+        let model = match &self.answered_by {
+            Some(answered_by) => &answered_by.model,
+            None => self.completion_model.model_str(),
+        };
+        let cost = self.cost(model, usage);
+        self.usage.record(usage, cost);
+    }
+
+    /// Usage of the semantic cache's embeddings is priced as its embedding model
+    fn record_embedding_usage(&mut self, usage: Option<TokenUsage>) {
+        let cost = self
+            .semantic_cache
+            .as_ref()
+            .and_then(|cache| self.cost(cache.embedder().model_str(), usage));
+        self.usage.record_embedding(usage, cost);
+    }
+
     /// Get a simple string response from a model
     pub async fn io_completion(&mut self) -> AgentResult<String> {
-        Ok(self.completion_model.get_io_completion(&self.cache).await?)
+        let completion = self.detailed_io_completion().await?;
+        completion
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.content)
+            .ok_or(anyhow!("Completion returned no choices").into())
+    }
+
+    /// Get a response from a model along with its finish reason, the model that served it,
+    /// the provider's request id, usage & latency
+    pub async fn detailed_io_completion(&mut self) -> AgentResult<IoCompletion> {
+        self.check_budget()?;
+        let read_cache = self.read_cache();
+        let scope = SemanticCache::scope(
+            self.completion_model.model_str(),
+            self.cache.ref_system_prompt_content(),
+        );
+        let embedding = match self.last_user_embedding().await {
+            Some((embedding, usage)) => {
+                self.record_embedding_usage(usage);
+                Some(embedding)
+            }
+            None => None,
+        };
+        if let (Some(semantic_cache), Some(embedding), true) =
+            (&self.semantic_cache, &embedding, read_cache)
+        {
+            if let Some(completion) = semantic_cache.lookup(scope, embedding) {
+                self.answered_by = None;
+                self.record_usage(None);
+                return Ok(IoCompletion {
+                    usage: None,
+                    latency: Duration::ZERO,
+                    ..completion
+                });
+            }
+        }
+
+        // Embedding the message may have spent the rest of the budget
+        self.check_budget()?;
+        let (completion, answered_by) = self
+            .completion_model
+            .get_io_completion(&self.cache, read_cache)
+            .await?;
+        self.answered_by = Some(answered_by);
+        self.record_usage(completion.usage);
+        if let (Some(semantic_cache), Some(embedding)) = (&self.semantic_cache, embedding) {
+            semantic_cache.insert(scope, embedding, completion.clone());
+        }
+        Ok(completion)
+    }
+
+    /// Embedding of the last user message for the semantic cache & the tokens it used. `None`
+    /// without a semantic cache or if embedding fails, in which case the cache is skipped
+    async fn last_user_embedding(&self) -> Option<(Vec<f32>, Option<TokenUsage>)> {
+        let semantic_cache = self.semantic_cache.as_ref()?;
+        let message = self
+            .cache
+            .as_ref()
+            .iter()
+            .rev()
+            .find(|m| m.role.actual() == &MessageRole::User)?;
+        semantic_cache
+            .embed(&message.content)
+            .await
+            .map_err(|err| warn!("Could not embed message for semantic cache: {}", err))
+            .ok()
+    }
+
+    /// Get `n` candidate responses from a model, each with the reason it stopped.
+    /// Providers that cannot return several choices from one request are sent `n` concurrent
+    /// requests. None of the candidates are added to the cache
+    pub async fn multi_choice_completion(&mut self, n: u32) -> AgentResult<Vec<CompletionChoice>> {
+        self.check_budget()?;
+        let read_cache = self.read_cache();
+        let (completion, answered_by) = self
+            .completion_model
+            .get_io_choices(&self.cache, n, read_cache)
+            .await?;
+        self.answered_by = Some(answered_by);
+        self.record_usage(completion.usage);
+        Ok(completion.choices)
     }
 
-    /// Get a streamed response from a model
+    /// Get a streamed response from a model. The message & its usage are added to the agent once
+    /// the stream is committed with `ProviderStreamHandler::commit`, or by `receive` when it ends
     pub async fn stream_completion(&mut self) -> AgentResult<ProviderStreamHandler> {
-        let cs = self
+        self.check_budget()?;
+        // Streams are never cached
+        self.bypass_cache = false;
+        let (cs, answered_by) = self
             .completion_model
             .get_stream_completion(&self.cache)
             .await?;
+        self.answered_by = Some(answered_by);
 
         Ok(cs.into())
     }
@@ -48,9 +254,52 @@ impl Agent {
         &mut self,
         function: Function,
     ) -> AgentResult<serde_json::Value> {
-        Ok(self
+        self.check_budget()?;
+        let read_cache = self.read_cache();
+        let ((json, usage), answered_by) = self
+            .completion_model
+            .get_fn_completion(&self.cache, function, read_cache)
+            .await?;
+        self.answered_by = Some(answered_by);
+        self.record_usage(usage);
+        Ok(json)
+    }
+
+    /// Like `function_completion`, but the arguments are streamed as they are generated.
+    /// The handler yields each fragment of argument JSON as a `StreamEvent::ToolCallDelta`, then
+    /// a `StreamEvent::ToolCall` holding the parsed arguments once the model has finished.
+    /// Call `commit` on the handler afterwards to record the usage
+    pub async fn stream_function_completion(
+        &mut self,
+        function: Function,
+    ) -> AgentResult<ProviderStreamHandler> {
+        self.check_budget()?;
+        // Streams are never cached
+        self.bypass_cache = false;
+        let (cs, answered_by) = self
             .completion_model
-            .get_fn_completion(&self.cache, function)
-            .await?)
+            .get_stream_fn_completion(&self.cache, function)
+            .await?;
+        self.answered_by = Some(answered_by);
+
+        Ok(cs)
+    }
+
+    /// Offer the model several functions at once, returns every call the model made.
+    /// `choice` decides whether the model may, must, or must call a specific function
+    pub async fn tool_completion(
+        &mut self,
+        functions: Vec<Function>,
+        choice: ToolChoice,
+    ) -> AgentResult<Vec<ToolCall>> {
+        self.check_budget()?;
+        let read_cache = self.read_cache();
+        let ((calls, usage), answered_by) = self
+            .completion_model
+            .get_tool_completion(&self.cache, &functions, &choice, read_cache)
+            .await?;
+        self.answered_by = Some(answered_by);
+        self.record_usage(usage);
+        Ok(calls)
     }
 }
@@ -0,0 +1,275 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+/// A cached response & when it was stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub value: Value,
+    /// Milliseconds since the unix epoch. Entries stored before this field existed read as 0, so
+    /// they expire under any ttl
+    #[serde(default)]
+    pub stored_at_ms: u64,
+}
+
+/// Storage for cached responses, keys are hex encoded hashes
+pub trait CacheBackend: Debug + Send + Sync {
+    fn get(&self, key: &str) -> Option<CacheEntry>;
+    fn insert(&self, key: &str, entry: CacheEntry);
+    fn remove(&self, key: &str);
+    fn clear(&self);
+}
+
+/// In memory backend that evicts the least recently used entry once full
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    entries: Mutex<(HashMap<String, CacheEntry>, VecDeque<String>)>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, order) = &mut *guard;
+        let entry = entries.get(key).cloned()?;
+        order.retain(|k| k != key);
+        order.push_back(key.to_owned());
+        Some(entry)
+    }
+
+    fn insert(&self, key: &str, entry: CacheEntry) {
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, order) = &mut *guard;
+        order.retain(|k| k != key);
+        order.push_back(key.to_owned());
+        entries.insert(key.to_owned(), entry);
+        while entries.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, order) = &mut *guard;
+        order.retain(|k| k != key);
+        entries.remove(key);
+    }
+
+    fn clear(&self) {
+        let mut guard = self.entries.lock().unwrap();
+        guard.0.clear();
+        guard.1.clear();
+    }
+}
+
+/// Backend keeping one JSON file per entry in a directory, so cached responses survive restarts.
+/// Failing to read or write a file is logged and treated as a miss
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    /// The directory is created if it does not exist
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        if let Err(err) = std::fs::create_dir_all(&dir) {
+            warn!("Could not create cache directory {:?}: {}", dir, err);
+        }
+        Self { dir }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+impl CacheBackend for DiskCache {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let contents = std::fs::read_to_string(self.path(key)).ok()?;
+        serde_json::from_str(&contents)
+            .map_err(|err| warn!("Corrupt cache entry {}: {}", key, err))
+            .ok()
+    }
+
+    fn insert(&self, key: &str, entry: CacheEntry) {
+        let result = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(std::fs::write(self.path(key), json)?));
+        if let Err(err) = result {
+            warn!("Could not write cache entry {}: {}", key, err);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = std::fs::remove_file(self.path(key));
+    }
+
+    fn clear(&self) {
+        let Ok(files) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for file in files.flatten() {
+            if file.path().extension().is_some_and(|ext| ext == "json") {
+                let _ = std::fs::remove_file(file.path());
+            }
+        }
+    }
+}
+
+/// Opt in cache of provider responses keyed by a hash of the request url & JSON body, so only
+/// identical requests to the same endpoint hit. Clones share the same backend.
+/// Cached completions carry no usage, as nothing was spent on them
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    backend: Arc<dyn CacheBackend>,
+    /// Entries older than this are treated as missing, `None` keeps them forever
+    pub ttl: Option<Duration>,
+}
+
+impl ResponseCache {
+    pub fn new(backend: impl CacheBackend + 'static) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            ttl: None,
+        }
+    }
+
+    /// In memory cache holding up to `capacity` responses
+    pub fn memory(capacity: usize) -> Self {
+        Self::new(MemoryCache::new(capacity))
+    }
+
+    /// Cache stored as files in `dir`
+    pub fn disk(dir: impl Into<PathBuf>) -> Self {
+        Self::new(DiskCache::new(dir))
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn clear(&self) {
+        self.backend.clear();
+    }
+
+    pub(crate) fn key(url: &str, request: &Value) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(url.as_bytes());
+        hasher.update(request.to_string().as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub(crate) fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let entry = self.backend.get(key)?;
+        if let Some(ttl) = self.ttl {
+            let age = Duration::from_millis(now_ms().saturating_sub(entry.stored_at_ms));
+            if age >= ttl {
+                self.backend.remove(key);
+                return None;
+            }
+        }
+        info!("Cache hit: {}", key);
+        serde_json::from_value(entry.value).ok()
+    }
+
+    pub(crate) fn insert<T: Serialize>(&self, key: &str, value: &T) {
+        match serde_json::to_value(value) {
+            Ok(value) => self.backend.insert(
+                key,
+                CacheEntry {
+                    value,
+                    stored_at_ms: now_ms(),
+                },
+            ),
+            Err(err) => warn!("Could not cache response: {}", err),
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn memory_cache_evicts_least_recently_used() {
+        let cache = ResponseCache::memory(2);
+        cache.insert("a", &1);
+        cache.insert("b", &2);
+        assert_eq!(cache.get::<i32>("a"), Some(1));
+        cache.insert("c", &3);
+
+        assert_eq!(cache.get::<i32>("b"), None);
+        assert_eq!(cache.get::<i32>("a"), Some(1));
+        assert_eq!(cache.get::<i32>("c"), Some(3));
+    }
+
+    #[test]
+    fn expired_entries_miss() {
+        let backend = MemoryCache::new(10);
+        backend.insert(
+            "old",
+            CacheEntry {
+                value: json!("stale"),
+                stored_at_ms: now_ms() - 120_000,
+            },
+        );
+        let cache = ResponseCache::new(backend).with_ttl(Duration::from_secs(60));
+        cache.insert("new", &"fresh");
+
+        assert_eq!(cache.get::<String>("old"), None);
+        assert_eq!(cache.get::<String>("new").as_deref(), Some("fresh"));
+
+        // Ttls under a second are honoured
+        let cache = ResponseCache::memory(10).with_ttl(Duration::from_millis(500));
+        cache.insert("brief", &"fresh");
+        assert_eq!(cache.get::<String>("brief").as_deref(), Some("fresh"));
+        std::thread::sleep(Duration::from_millis(600));
+        assert_eq!(cache.get::<String>("brief"), None);
+
+        let saved: CacheEntry =
+            serde_json::from_value(json!({"value": "stale", "stored_at": 1_700_000_000})).unwrap();
+        assert_eq!(saved.stored_at_ms, 0);
+    }
+
+    #[test]
+    fn disk_cache_persists_between_instances() {
+        let dir = std::env::temp_dir().join(format!("espionox-cache-{}", uuid::Uuid::new_v4()));
+        let key = ResponseCache::key("http://localhost", &json!({"prompt": "hi"}));
+        ResponseCache::disk(&dir).insert(&key, &json!({"reply": "hello"}));
+
+        let cache = ResponseCache::disk(&dir);
+        assert_eq!(cache.get::<Value>(&key), Some(json!({"reply": "hello"})));
+        cache.clear();
+        assert_eq!(cache.get::<Value>(&key), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,15 +1,23 @@
 use super::{
     super::{
-        error::CompletionResult,
+        error::{CompletionResult, ProviderResponseError},
+        functions::{serialize_params, Function, ToolCall, ToolChoice},
         inference::{CompletionRequest, CompletionRequestBuilder},
+        streaming::FunctionStreamRequest,
+        usage::TokenUsage,
         ModelParameters,
     },
-    requests::AnthropicIoRequest,
+    requests::{AnthropicIoRequest, AnthropicResponse, AnthropicResponseContent, AnthropicUsage},
+    streaming::AnthropicStreamResponse,
+};
+use crate::{
+    agents::memory::{Message, MessageStack},
+    language_models::secret::sensitive_header,
 };
-use crate::agents::memory::{Message, MessageStack};
 use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+use tracing::info;
 
 #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub enum AnthropicCompletionModel {
@@ -23,6 +31,14 @@ const OPUS_MODEL_STR: &str = "claude-3-opus-20240229";
 const SONNET_MODEL_STR: &str = "claude-3-sonnet-20240229";
 const HAIKU_MODEL_STR: &str = "claude-3-haiku-20240307";
 
+/// `ModelParameters` Anthropic has no equivalent for
+const UNSUPPORTED_PARAMS: &[&str] = &[
+    "frequency_penalty",
+    "presence_penalty",
+    "seed",
+    "logit_bias",
+];
+
 impl CompletionRequestBuilder for AnthropicCompletionModel {
     fn model_str(&self) -> &str {
         match self {
@@ -38,7 +54,9 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
 
     fn headers(&self, api_key: &str) -> HeaderMap {
         let mut map = HeaderMap::new();
-        map.insert("x-api-key", format!("{}", api_key).parse().unwrap());
+        if let Ok(value) = sensitive_header(api_key) {
+            map.insert("x-api-key", value);
+        }
         map.insert("anthropic-version", "2023-06-01".parse().unwrap());
         map.insert("content-type", "application/json".parse().unwrap());
         map
@@ -76,6 +94,7 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
         stack: &MessageStack,
         params: &ModelParameters,
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        params.ensure_supported("Anthropic", UNSUPPORTED_PARAMS)?;
         Ok(Box::new(AnthropicIoRequest::new(
             stack, params, *self, false,
         )))
@@ -86,10 +105,79 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
         stack: &MessageStack,
         params: &ModelParameters,
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        params.ensure_supported("Anthropic", UNSUPPORTED_PARAMS)?;
         Ok(Box::new(AnthropicIoRequest::new(
             stack, params, *self, true,
         )))
     }
+
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        params.ensure_supported("Anthropic", UNSUPPORTED_PARAMS)?;
+        // Anthropic's tool use takes the same body as a regular message request, plus the tools
+        let mut req = serde_json::to_value(AnthropicIoRequest::new(stack, params, *self, false))?;
+        let tools = functions
+            .iter()
+            .map(|function| {
+                json!({
+                    "name": function.name,
+                    "description": function.description,
+                    "input_schema": serialize_params(&function.params),
+                })
+            })
+            .collect::<Vec<Value>>();
+        info!("tools serialized: {:?}", tools);
+        req["tool_choice"] = match choice {
+            ToolChoice::Auto => json!({"type": "auto"}),
+            ToolChoice::Required => json!({"type": "any"}),
+            ToolChoice::Named(name) => json!({"type": "tool", "name": name}),
+        };
+        req["tools"] = tools.into();
+        Ok(req)
+    }
+
+    fn into_function_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        function: Function,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        let mut req = self.serialize_function(stack, params, function)?;
+        req["stream"] = true.into();
+        Ok(Box::new(
+            FunctionStreamRequest::<AnthropicStreamResponse>::new(req),
+        ))
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<Vec<ToolCall>> {
+        match serde_json::from_value::<AnthropicResponse>(response_json.clone())? {
+            AnthropicResponse::Success(suc) => Ok(suc
+                .content
+                .into_iter()
+                .filter_map(|c| match c {
+                    AnthropicResponseContent::ToolUse { id, name, input } => Some(ToolCall {
+                        id,
+                        name,
+                        arguments: input,
+                    }),
+                    _ => None,
+                })
+                .collect()),
+            AnthropicResponse::Err { error } => Err(error.into_error(&response_json)),
+        }
+    }
+
+    fn process_usage(&self, response_json: &Value) -> Option<TokenUsage> {
+        let usage = response_json.get("usage")?.clone();
+        serde_json::from_value::<AnthropicUsage>(usage)
+            .ok()
+            .map(TokenUsage::from)
+    }
 }
 
 #[cfg(test)]
@@ -119,4 +207,137 @@ mod tests {
             MessageStack::try_from(vals.as_array().unwrap().to_owned()).unwrap();
         assert_eq!(5, stack.len());
     }
+
+    #[test]
+    fn anthropic_function_serialized_as_tool() {
+        let mut stack = MessageStack::new("SYSTEM");
+        stack.push(Message::new_user("What's the weather in Detroit?"));
+        let function = Function::try_from(
+            r#"get_weather(location!: string)
+            where
+                i am 'get the weather'
+                location is 'the city'
+            "#,
+        )
+        .unwrap();
+        let handler = AnthropicCompletionModel::default();
+        let req = handler
+            .serialize_function(&stack, &ModelParameters::default(), function)
+            .unwrap();
+
+        assert_eq!(req["system"], "SYSTEM");
+        assert_eq!(req["max_tokens"], 1000);
+        assert_eq!(req["stream"], false);
+        assert_eq!(
+            req["tool_choice"],
+            json!({"type": "tool", "name": "get_weather"})
+        );
+        assert_eq!(req["tools"][0]["name"], "get_weather");
+        assert_eq!(req["tools"][0]["description"], "get the weather");
+        assert_eq!(
+            req["tools"][0]["input_schema"]["properties"]["location"],
+            json!({"type": "string", "description": "the city"})
+        );
+        assert_eq!(
+            req["tools"][0]["input_schema"]["required"],
+            json!(["location"])
+        );
+    }
+
+    #[test]
+    fn anthropic_tool_choice_serialized() {
+        let mut stack = MessageStack::new("SYSTEM");
+        stack.push(Message::new_user("What's the weather in Detroit?"));
+        let functions = vec![
+            Function::try_from("get_weather(location!: string)").unwrap(),
+            Function::try_from("get_time(timezone!: string)").unwrap(),
+        ];
+        let handler = AnthropicCompletionModel::default();
+        let params = ModelParameters::default();
+
+        let req = handler
+            .serialize_tools(&stack, &params, &functions, &ToolChoice::Auto)
+            .unwrap();
+        assert_eq!(req["tool_choice"], json!({"type": "auto"}));
+        assert_eq!(req["tools"].as_array().unwrap().len(), 2);
+
+        let req = handler
+            .serialize_tools(&stack, &params, &functions, &ToolChoice::Required)
+            .unwrap();
+        assert_eq!(req["tool_choice"], json!({"type": "any"}));
+    }
+
+    #[test]
+    fn anthropic_sampling_params_serialized() {
+        let stack = MessageStack::new("SYSTEM");
+        let params = ModelParameters {
+            temperature: Some(0.5),
+            top_p: Some(0.9),
+            top_k: Some(40),
+            stop: Some(vec!["END".to_owned()]),
+            ..Default::default()
+        };
+        let handler = AnthropicCompletionModel::default();
+        let req = handler
+            .into_io_req(&stack, &params)
+            .unwrap()
+            .as_json()
+            .unwrap();
+        assert_eq!(req["temperature"], json!(0.5f32));
+        assert_eq!(req["top_p"], json!(0.9f32));
+        assert_eq!(req["top_k"], 40);
+        assert_eq!(req["stop_sequences"], json!(["END"]));
+
+        let params = ModelParameters {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let err = handler.into_stream_req(&stack, &params).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Anthropic does not support the `seed` parameter"
+        );
+    }
+
+    #[test]
+    fn anthropic_tool_use_response_processed() {
+        let response = json!({
+            "id": "msg_01Aq9w938a90dw8q",
+            "model": "claude-3-haiku-20240307",
+            "stop_reason": "tool_use",
+            "role": "assistant",
+            "type": "message",
+            "content": [
+                {
+                    "type": "text",
+                    "text": "<thinking>I need to use the get_weather tool</thinking>"
+                },
+                {
+                    "type": "tool_use",
+                    "id": "toolu_01A09q90qw90lq917835lq9",
+                    "name": "get_weather",
+                    "input": {"location": "Detroit, MI"}
+                }
+            ],
+            "usage": {"input_tokens": 10, "output_tokens": 20}
+        });
+        let handler = AnthropicCompletionModel::default();
+        let args = handler.process_function_response(response.clone()).unwrap();
+        assert_eq!(args, json!({"location": "Detroit, MI"}));
+
+        let calls = handler.process_tools_response(response.clone()).unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "toolu_01A09q90qw90lq917835lq9");
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(
+            handler.process_usage(&response),
+            Some(TokenUsage::new(10, 20))
+        );
+
+        let response = json!({
+            "type": "error",
+            "error": {"type": "invalid_request_error", "message": "bad"}
+        });
+        assert!(handler.process_function_response(response).is_err());
+    }
 }
@@ -1,7 +1,8 @@
 use super::{
     super::{
         error::{CompletionResult, ProviderResponseError},
-        inference::{CompletionRequest, CompletionRequestBuilder, CompletionResponse},
+        inference::{CompletionChoice, CompletionRequest, CompletionRequestBuilder, IoCompletion},
+        usage::TokenUsage,
         ModelParameters,
     },
     builder::AnthropicCompletionModel,
@@ -11,19 +12,24 @@ use crate::agents::memory::{MessageRole, MessageStack};
 use crate::language_models::completions::error::CompletionError;
 use crate::language_models::completions::inference::ProcessResponseReturn;
 use crate::language_models::completions::streaming::{
-    CompletionStream, ProviderStreamHandler, StreamedCompletionHandler,
+    sse::sse_json_stream, ProviderStreamHandler, StreamedCompletionHandler,
 };
-use futures::TryStreamExt;
-use reqwest_streams::JsonStreamResponse;
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::time::Duration;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct AnthropicIoRequest {
     pub model: String,
     pub messages: Value,
-    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
     pub system: String,
     pub max_tokens: u32,
     pub stream: bool,
@@ -45,14 +51,13 @@ impl AnthropicIoRequest {
             .map(|m| m.content.as_str())
             .collect::<Vec<&str>>()
             .join(".");
-        let temperature = match params.temperature().ok() {
-            Some(t) => t,
-            None => 0.7,
-        };
         Self {
             model: typ.model_str().to_string(),
             messages: typ.serialize_messages(&sans_system_stack),
-            temperature,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: params.top_k,
+            stop_sequences: params.stop.clone(),
             max_tokens: params.max_tokens.unwrap_or(1000),
             system,
             stream,
@@ -69,27 +74,31 @@ impl CompletionRequest for AnthropicIoRequest {
         Box::pin(async move {
             match self.stream {
                 false => {
-                    let json = response.json().await?;
+                    let json: Value = response.json().await?;
                     tracing::warn!("got response:  {json:#?}");
-                    let response: AnthropicResponse = serde_json::from_value(json)?;
+                    let response: AnthropicResponse = serde_json::from_value(json.clone())?;
                     match response {
-                        AnthropicResponse::Success(mut suc) => {
-                            let content = suc.content.remove(0).text;
-                            Ok(CompletionResponse::from(content))
+                        AnthropicResponse::Success(suc) => {
+                            let content = suc
+                                .content
+                                .into_iter()
+                                .find_map(|c| match c {
+                                    AnthropicResponseContent::Text { text } => Some(text),
+                                    _ => None,
+                                })
+                                .ok_or(CompletionError::from(anyhow!(
+                                    "No text content in success message"
+                                )))?;
+                            let choice = CompletionChoice::new(content, suc.stop_reason.as_deref());
+                            Ok(IoCompletion::new(vec![choice], Some(suc.usage.into()))
+                                .with_model(suc.model)
+                                .into())
                         }
-                        AnthropicResponse::Err { error } => Err(error.into_error()),
+                        AnthropicResponse::Err { error } => Err(error.into_error(&json)),
                     }
                 }
                 true => {
-                    let response_stream: CompletionStream = Box::new(
-                        tokio::time::timeout(Duration::from_secs(10), async {
-                            response
-                                .json_array_stream::<Value>(1024)
-                                .map_err(|err| err.into())
-                        })
-                        .await
-                        .map_err(|_| CompletionError::StreamTimeout)?,
-                    );
+                    let response_stream = sse_json_stream(response.bytes_stream());
                     let handler: ProviderStreamHandler =
                         StreamedCompletionHandler::<AnthropicStreamResponse>::from(response_stream)
                             .into();
@@ -109,19 +118,38 @@ pub enum AnthropicResponse {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AnthropicSuccess {
-    content: Vec<AnthropicResponseContent>,
-    usage: AnthropicUsage,
+    pub(super) content: Vec<AnthropicResponseContent>,
+    model: String,
+    stop_reason: Option<String>,
+    pub(super) usage: AnthropicUsage,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AnthropicError {
-    message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+impl ProviderResponseError for AnthropicError {
+    fn message(&self) -> &str {
+        &self.message
+    }
+    fn error_type(&self) -> Option<&str> {
+        Some(&self.error_type)
+    }
 }
-impl ProviderResponseError for AnthropicError {}
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct AnthropicResponseContent {
-    text: String,
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum AnthropicResponseContent {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "tool_use")]
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -129,3 +157,9 @@ pub struct AnthropicUsage {
     input_tokens: i32,
     output_tokens: i32,
 }
+
+impl From<AnthropicUsage> for TokenUsage {
+    fn from(value: AnthropicUsage) -> Self {
+        TokenUsage::new(value.input_tokens as u32, value.output_tokens as u32)
+    }
+}
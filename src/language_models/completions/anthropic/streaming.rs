@@ -1,7 +1,60 @@
-use crate::language_models::completions::streaming::{CompletionStreamStatus, StreamResponse};
+use crate::language_models::completions::{
+    streaming::{StreamEvent, StreamResponse},
+    usage::TokenUsage,
+};
 use serde::Deserialize;
 
-impl StreamResponse for AnthropicStreamResponse {}
+impl StreamResponse for AnthropicStreamResponse {
+    /// Input tokens are reported when the message starts, output tokens when it ends.
+    /// Tool call deltas are indexed by content block
+    fn into_events(self) -> Vec<StreamEvent> {
+        let usage = |usage: Usage| {
+            StreamEvent::Usage(TokenUsage::new(usage.input_tokens, usage.output_tokens))
+        };
+        match self {
+            Self::MessageStart { message } => vec![usage(message.usage)],
+            Self::ContentBlockStart {
+                index,
+                content_block,
+            } => match content_block {
+                ContentBlock::Text { text } => vec![StreamEvent::TextDelta(text)],
+                ContentBlock::ToolUse { id, name } => vec![StreamEvent::ToolCallDelta {
+                    index,
+                    id: Some(id),
+                    name: Some(name),
+                    arguments: String::new(),
+                }],
+                ContentBlock::Other => vec![],
+            },
+            Self::Ping => vec![StreamEvent::Ping],
+            Self::ContentBlockDelta { index, delta } => match delta {
+                Delta::Text { text } => vec![StreamEvent::TextDelta(text)],
+                Delta::InputJson { partial_json } => vec![StreamEvent::ToolCallDelta {
+                    index,
+                    id: None,
+                    name: None,
+                    arguments: partial_json,
+                }],
+                Delta::Other => vec![],
+            },
+            Self::MessageDelta {
+                delta,
+                usage: delta_usage,
+            } => delta
+                .stop_reason
+                .map(StreamEvent::Stop)
+                .into_iter()
+                .chain([usage(delta_usage)])
+                .collect(),
+            Self::ContentBlockStop { .. } | Self::MessageStop => vec![],
+        }
+    }
+
+    /// The final `message_delta` carrying output usage comes before the message stops
+    fn is_final(&self) -> bool {
+        matches!(self, Self::MessageStop)
+    }
+}
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type")]
@@ -39,47 +92,112 @@ struct Message {
 }
 
 #[derive(Debug, Deserialize, Clone)]
-struct ContentBlock {
-    #[serde(rename = "type")]
-    block_type: String,
-    text: String,
+#[serde(tag = "type")]
+enum ContentBlock {
+    #[serde(rename = "text")]
+    Text { text: String },
+    /// Its input arrives as `input_json_delta`s
+    #[serde(rename = "tool_use")]
+    ToolUse { id: String, name: String },
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type")]
 enum Delta {
     #[serde(rename = "text_delta")]
-    TextDelta { text: String },
-}
-
-impl Delta {
-    fn inner_text(self) -> String {
-        match self {
-            Self::TextDelta { text } => text,
-        }
-    }
+    Text { text: String },
+    #[serde(rename = "input_json_delta")]
+    InputJson { partial_json: String },
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct MessageDelta {
     stop_reason: Option<String>,
-    stop_sequence: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct Usage {
+    /// Left out of `message_delta` events
+    #[serde(default)]
     input_tokens: u32,
     output_tokens: u32,
 }
 
-impl Into<CompletionStreamStatus> for AnthropicStreamResponse {
-    fn into(self) -> CompletionStreamStatus {
-        match self {
-            Self::MessageStop | Self::ContentBlockStop { .. } => CompletionStreamStatus::Finished,
-            Self::ContentBlockDelta { delta, .. } => {
-                return CompletionStreamStatus::Working(delta.inner_text());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn anthropic_stream_usage_read() {
+        let start: AnthropicStreamResponse = serde_json::from_value(json!({
+            "type": "message_start",
+            "message": {
+                "id": "msg_1nZdL29xx5MUA1yADyHTEsnR8uuvGzszyY",
+                "type": "message",
+                "role": "assistant",
+                "content": [],
+                "model": "claude-3-haiku-20240307",
+                "stop_reason": null,
+                "stop_sequence": null,
+                "usage": {"input_tokens": 25, "output_tokens": 1}
+            }
+        }))
+        .unwrap();
+        let delta: AnthropicStreamResponse = serde_json::from_value(json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": "end_turn", "stop_sequence": null},
+            "usage": {"output_tokens": 15}
+        }))
+        .unwrap();
+
+        let mut usage = TokenUsage::default();
+        for event in start.into_events().into_iter().chain(delta.into_events()) {
+            if let StreamEvent::Usage(u) = event {
+                usage.update(u);
             }
-            _ => CompletionStreamStatus::Working("".to_string()),
         }
+        assert_eq!(usage, TokenUsage::new(25, 15));
+
+        let stop: AnthropicStreamResponse =
+            serde_json::from_value(json!({"type": "content_block_stop", "index": 0})).unwrap();
+        assert!(!stop.is_final() && stop.into_events().is_empty());
+
+        let tool: AnthropicStreamResponse = serde_json::from_value(json!({
+            "type": "content_block_start",
+            "index": 1,
+            "content_block": {"type": "tool_use", "id": "toolu_01T1x1fJ34qAmk2tNTrN7Up6", "name": "get_weather", "input": {}}
+        }))
+        .unwrap();
+        let input: AnthropicStreamResponse = serde_json::from_value(json!({
+            "type": "content_block_delta",
+            "index": 1,
+            "delta": {"type": "input_json_delta", "partial_json": "{\"location\": \"San Fra"}
+        }))
+        .unwrap();
+        assert_eq!(
+            tool.into_events()
+                .into_iter()
+                .chain(input.into_events())
+                .collect::<Vec<_>>(),
+            [
+                StreamEvent::ToolCallDelta {
+                    index: 1,
+                    id: Some("toolu_01T1x1fJ34qAmk2tNTrN7Up6".to_owned()),
+                    name: Some("get_weather".to_owned()),
+                    arguments: String::new()
+                },
+                StreamEvent::ToolCallDelta {
+                    index: 1,
+                    id: None,
+                    name: None,
+                    arguments: "{\"location\": \"San Fra".to_owned()
+                }
+            ]
+        );
     }
 }
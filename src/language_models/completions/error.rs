@@ -1,7 +1,20 @@
 use tracing::warn;
 
-use crate::errors::error_chain_fmt;
-use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use super::streaming::StreamError;
+use crate::{
+    errors::error_chain_fmt,
+    language_models::{
+        credentials::CredentialError,
+        retry::{is_retryable_request, is_retryable_status},
+    },
+};
+use anyhow::anyhow;
+use reqwest::{Response, StatusCode};
+use serde_json::Value;
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    time::Duration,
+};
 
 pub type CompletionResult<T> = Result<T, CompletionError>;
 
@@ -11,16 +24,216 @@ pub enum CompletionError {
     Undefined(#[from] anyhow::Error),
     Json(#[from] serde_json::Error),
     Request(#[from] reqwest::Error),
-    Provider(String),
+    /// The model's api key could not be resolved
+    Credentials(#[from] CredentialError),
+    /// An error from a provider that could not be classified as any of the variants below
+    Provider(ProviderError),
+    /// Too many requests or tokens, or out of quota
+    RateLimited(ProviderError),
+    /// The prompt does not fit in the model's context window
+    ContextLengthExceeded(ProviderError),
+    /// Missing, invalid or unauthorized api key
+    Authentication(ProviderError),
+    /// The provider is temporarily overloaded
+    Overloaded(ProviderError),
+    /// The request was malformed or used an unknown model
+    InvalidRequest(ProviderError),
+    /// The prompt or completion was blocked by the provider's content filter
+    ContentFiltered(ProviderError),
+    /// Any other 5xx response
+    Server(ProviderError),
     FunctionNotImplemented,
+    /// A header name given by the caller is not a valid HTTP header name
+    InvalidHeader(String),
     StreamTimeout,
+    /// The model's `timeout` elapsed before it answered
+    Timeout(Duration),
     CouldNotCoerce,
+    /// A `ModelParameters` field was set that the provider has no equivalent for
+    UnsupportedParameter {
+        provider: &'static str,
+        parameter: &'static str,
+    },
 }
 
+/// An error a provider sent in a successful response, parsed into the provider's own type
 pub trait ProviderResponseError: Debug {
-    fn into_error(&self) -> CompletionError {
+    fn message(&self) -> &str;
+    /// The provider's error type, such as `invalid_request_error`
+    fn error_type(&self) -> Option<&str> {
+        None
+    }
+    /// The provider's error code, such as `context_length_exceeded`
+    fn code(&self) -> Option<&str> {
+        None
+    }
+    /// `body` is the whole response body the error was parsed from, it is kept as
+    /// `ProviderError::body`
+    fn into_error(&self, body: &Value) -> CompletionError {
         warn!("Coercing to completion error: {:?}", self);
-        CompletionError::Provider(format!("Provider error: {:?}", self))
+        ProviderError {
+            status: None,
+            error_type: self.error_type().map(str::to_owned),
+            code: self.code().map(str::to_owned),
+            message: self.message().to_owned(),
+            body: body.to_string(),
+        }
+        .into()
+    }
+}
+
+/// Details of an error returned by a provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderError {
+    /// HTTP status, `None` if the error came in a successful response, for example mid stream
+    pub status: Option<u16>,
+    /// The provider's error type, such as `invalid_request_error` or `overloaded_error`
+    pub error_type: Option<String>,
+    /// OpenAi's error code, such as `context_length_exceeded`
+    pub code: Option<String>,
+    pub message: String,
+    /// The raw response body
+    pub body: String,
+}
+
+impl ProviderError {
+    /// Reads OpenAi & Anthropic style `{"error": {"type", "code", "message"}}` bodies as well as
+    /// Ollama style `{"error": "message"}` ones. Anything else becomes the message as is
+    pub fn from_body(status: Option<u16>, body: String) -> Self {
+        let json = serde_json::from_str::<Value>(&body).unwrap_or_default();
+        let field = |name: &str| json["error"][name].as_str().map(str::to_owned);
+        let message = field("message")
+            .or(json["error"].as_str().map(str::to_owned))
+            .unwrap_or(body.clone());
+        Self {
+            status,
+            error_type: field("type"),
+            code: field("code"),
+            message,
+            body,
+        }
+    }
+
+    /// Turns any non 2xx response into an error
+    pub(crate) async fn check_response(response: Response) -> Result<Response, ProviderError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        warn!("Provider responded with {}: {}", status, body);
+        Err(Self::from_body(Some(status.as_u16()), body))
+    }
+
+    fn is(&self, values: &[&str]) -> bool {
+        [&self.error_type, &self.code]
+            .into_iter()
+            .flatten()
+            .any(|v| values.contains(&v.as_str()))
+    }
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if let Some(status) = self.status {
+            write!(f, "{} ", status)?;
+        }
+        if let Some(typ) = self.code.as_ref().or(self.error_type.as_ref()) {
+            write!(f, "{}: ", typ)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<ProviderError> for CompletionError {
+    fn from(err: ProviderError) -> Self {
+        let status = err.status.unwrap_or_default();
+        let message = err.message.to_lowercase();
+        if status == 429
+            || err.is(&[
+                "rate_limit_error",
+                "rate_limit_exceeded",
+                "insufficient_quota",
+            ])
+        {
+            Self::RateLimited(err)
+        } else if status == 529 || err.is(&["overloaded_error"]) {
+            Self::Overloaded(err)
+        } else if status == 401
+            || status == 403
+            || err.is(&[
+                "authentication_error",
+                "permission_error",
+                "invalid_api_key",
+            ])
+        {
+            Self::Authentication(err)
+        } else if err.is(&["context_length_exceeded"])
+            || message.contains("prompt is too long")
+            || message.contains("maximum context length")
+        {
+            Self::ContextLengthExceeded(err)
+        } else if err.is(&["content_filter", "content_policy_violation"]) {
+            Self::ContentFiltered(err)
+        } else if StatusCode::from_u16(status).is_ok_and(|s| s.is_server_error())
+            || err.is(&["api_error", "server_error"])
+        {
+            Self::Server(err)
+        } else if (400..500).contains(&status)
+            || err.is(&["invalid_request_error", "not_found_error"])
+        {
+            Self::InvalidRequest(err)
+        } else {
+            Self::Provider(err)
+        }
+    }
+}
+
+/// Errors a provider sends in place of the first chunk of a stream are classified like error
+/// responses
+impl From<StreamError> for CompletionError {
+    fn from(err: StreamError) -> Self {
+        match err {
+            StreamError::StreamRecievedErr(json) => {
+                ProviderError::from_body(None, json.to_string()).into()
+            }
+            err => Self::Undefined(anyhow!(err)),
+        }
+    }
+}
+
+impl CompletionError {
+    /// The provider's error details, if this error came from a provider
+    pub fn provider_error(&self) -> Option<&ProviderError> {
+        match self {
+            Self::RateLimited(err)
+            | Self::ContextLengthExceeded(err)
+            | Self::Authentication(err)
+            | Self::Overloaded(err)
+            | Self::InvalidRequest(err)
+            | Self::ContentFiltered(err)
+            | Self::Server(err)
+            | Self::Provider(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Whether making the same request again may succeed. Running out of quota is reported as
+    /// rate limiting but is not retryable
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited(err) => err.code.as_deref() != Some("insufficient_quota"),
+            // Request timeouts & conflicts
+            Self::InvalidRequest(err) => err
+                .status
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .is_some_and(is_retryable_status),
+            Self::Overloaded(_) | Self::Server(_) | Self::StreamTimeout | Self::Timeout(_) => true,
+            Self::Request(err) => is_retryable_request(err),
+            _ => false,
+        }
     }
 }
 impl Debug for CompletionError {
@@ -35,11 +248,93 @@ impl Display for CompletionError {
             Self::Json(err) => err.to_string(),
             Self::Undefined(err) => err.to_string(),
             Self::Request(err) => err.to_string(),
+            Self::Credentials(err) => format!("Could not get api key: {}", err),
             Self::StreamTimeout => "Stream Timeout".to_string(),
+            Self::Timeout(timeout) => format!("No answer within {:?}", timeout),
             Self::Provider(err) => err.to_string(),
+            Self::RateLimited(err) => format!("Rate limited: {}", err),
+            Self::ContextLengthExceeded(err) => format!("Context length exceeded: {}", err),
+            Self::Authentication(err) => format!("Authentication failed: {}", err),
+            Self::Overloaded(err) => format!("Provider overloaded: {}", err),
+            Self::InvalidRequest(err) => format!("Invalid request: {}", err),
+            Self::ContentFiltered(err) => format!("Content filtered: {}", err),
+            Self::Server(err) => format!("Provider server error: {}", err),
             Self::CouldNotCoerce => "Could Not Coerce".to_string(),
             Self::FunctionNotImplemented => "Function Not Implemented".to_string(),
+            Self::InvalidHeader(name) => format!("{:?} is not a valid header name", name),
+            Self::UnsupportedParameter {
+                provider,
+                parameter,
+            } => format!(
+                "{} does not support the `{}` parameter",
+                provider, parameter
+            ),
         };
         write!(f, "{}", display)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn classify(status: u16, body: Value) -> CompletionError {
+        ProviderError::from_body(Some(status), body.to_string()).into()
+    }
+
+    #[test]
+    fn provider_errors_are_classified() {
+        let err = classify(
+            400,
+            json!({"error": {"message": "This model's maximum context length is 8192 tokens", "type": "invalid_request_error", "param": "messages", "code": "context_length_exceeded"}}),
+        );
+        assert!(matches!(err, CompletionError::ContextLengthExceeded(_)));
+        assert!(!err.is_retryable());
+
+        let err = classify(
+            400,
+            json!({"type": "error", "error": {"type": "invalid_request_error", "message": "prompt is too long: 210000 tokens > 200000 maximum"}}),
+        );
+        assert!(matches!(err, CompletionError::ContextLengthExceeded(_)));
+
+        let err = classify(
+            529,
+            json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}),
+        );
+        assert!(matches!(err, CompletionError::Overloaded(_)));
+        assert!(err.is_retryable());
+
+        let err = classify(
+            429,
+            json!({"error": {"message": "You exceeded your current quota", "type": "insufficient_quota", "code": "insufficient_quota"}}),
+        );
+        assert!(matches!(err, CompletionError::RateLimited(_)));
+        assert!(!err.is_retryable());
+
+        let err = classify(
+            401,
+            json!({"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "code": "invalid_api_key"}}),
+        );
+        assert!(matches!(err, CompletionError::Authentication(_)));
+
+        let err = classify(404, json!({"error": "model 'llama9' not found"}));
+        let provider = err.provider_error().unwrap();
+        assert!(matches!(err, CompletionError::InvalidRequest(_)));
+        assert_eq!(provider.status, Some(404));
+        assert_eq!(provider.message, "model 'llama9' not found");
+
+        let err = classify(500, json!("oops"));
+        assert!(matches!(err, CompletionError::Server(_)));
+        assert!(err.is_retryable());
+
+        let body = json!({"error": {"message": "Something odd", "code": "odd_error"}});
+        let err = ProviderError::from_body(None, body.to_string()).into();
+        let CompletionError::Provider(provider) = &err else {
+            panic!("expected an unclassified provider error, got {:?}", err);
+        };
+        assert_eq!(provider.code.as_deref(), Some("odd_error"));
+        assert_eq!(provider.body, body.to_string());
+        assert_eq!(err.provider_error(), Some(provider));
+    }
+}
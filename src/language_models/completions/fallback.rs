@@ -0,0 +1,169 @@
+use super::{
+    error::{CompletionError, CompletionResult},
+    CompletionModel, CompletionProvider,
+};
+use futures::{stream::FuturesUnordered, Future, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{iter::once, time::Duration};
+use tracing::warn;
+
+/// A result along with the model that produced it
+pub(crate) type FallbackResult<T> = CompletionResult<(T, AnsweredBy)>;
+
+/// How a completion model's fallbacks are used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FallbackMode {
+    /// Try each model only once the one before it has failed
+    #[default]
+    Sequential,
+    /// Start the next model whenever the delay passes without an answer, or as soon as a request
+    /// fails. The first success wins and every other request still in flight is cancelled.
+    /// Cancelled requests may still be billed by their provider but are not recorded as usage
+    Hedged(Duration),
+}
+
+impl FallbackMode {
+    /// Send the request to every model at once
+    pub fn race() -> Self {
+        Self::Hedged(Duration::ZERO)
+    }
+}
+
+/// Which model of a fallback chain answered a completion
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnsweredBy {
+    /// Position in the chain, 0 is the primary model and 1 the first fallback
+    pub index: usize,
+    /// Name of the provider, such as `OpenAi` or `Anthropic`
+    pub provider: String,
+    /// The model identifier sent to the provider
+    pub model: String,
+}
+
+impl AnsweredBy {
+    fn new(index: usize, model: &CompletionModel) -> Self {
+        Self {
+            index,
+            provider: model.provider.name().to_owned(),
+            model: model.model_str().to_owned(),
+        }
+    }
+
+    /// Whether a fallback answered rather than the primary model
+    pub fn is_fallback(&self) -> bool {
+        self.index > 0
+    }
+}
+
+impl CompletionProvider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OpenAi(_) => "OpenAi",
+            Self::Anthropic(_) => "Anthropic",
+            Self::OpenAiCompatible(_) => "OpenAi compatible",
+            Self::Ollama(_) => "Ollama",
+            Self::Custom(_) => "Custom",
+        }
+    }
+}
+
+impl CompletionModel {
+    /// Tries `request` with this model & its fallbacks as the model's `FallbackMode` says.
+    /// Fallbacks' own fallbacks are not tried
+    pub(crate) async fn try_chain<'m, T, F, Fut>(&'m self, request: F) -> FallbackResult<T>
+    where
+        F: Fn(&'m CompletionModel) -> Fut,
+        Fut: Future<Output = CompletionResult<T>>,
+    {
+        match self.fallback_mode {
+            FallbackMode::Hedged(delay) if !self.fallbacks.is_empty() => {
+                self.try_hedged(delay, request).await
+            }
+            _ => self.try_sequential(request).await,
+        }
+    }
+
+    /// Only retryable errors & timeouts fall through, any other error is returned right away
+    async fn try_sequential<'m, T, F, Fut>(&'m self, request: F) -> FallbackResult<T>
+    where
+        F: Fn(&'m CompletionModel) -> Fut,
+        Fut: Future<Output = CompletionResult<T>>,
+    {
+        let last = self.fallbacks.len();
+        for (index, model) in once(self).chain(self.fallbacks.iter()).enumerate() {
+            match model.within_timeout(request(model)).await {
+                Ok(response) => return Ok((response, AnsweredBy::new(index, model))),
+                Err(err) if index < last && err.is_retryable() => {
+                    warn!(
+                        "{} {} failed, falling back: {}",
+                        model.provider.name(),
+                        model.model_str(),
+                        err
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        unreachable!("a chain always contains at least the primary model")
+    }
+
+    /// Any error starts the next model right away. If every model fails, the error of the
+    /// earliest model in the chain is returned
+    async fn try_hedged<'m, T, F, Fut>(&'m self, delay: Duration, request: F) -> FallbackResult<T>
+    where
+        F: Fn(&'m CompletionModel) -> Fut,
+        Fut: Future<Output = CompletionResult<T>>,
+    {
+        let models: Vec<&CompletionModel> = once(self).chain(self.fallbacks.iter()).collect();
+        let start = |index: usize| {
+            let model = models[index];
+            let request = model.within_timeout(request(model));
+            async move { (index, request.await) }
+        };
+        // Dropping this cancels every request still in flight
+        let mut in_flight = FuturesUnordered::new();
+        in_flight.push(start(0));
+        let mut started = 1;
+        let mut errors = vec![];
+        loop {
+            tokio::select! {
+                Some((index, result)) = in_flight.next() => match result {
+                    Ok(response) => return Ok((response, AnsweredBy::new(index, models[index]))),
+                    Err(err) => {
+                        warn!(
+                            "{} {} failed while hedging: {}",
+                            models[index].provider.name(),
+                            models[index].model_str(),
+                            err
+                        );
+                        errors.push((index, err));
+                        if started < models.len() {
+                            in_flight.push(start(started));
+                            started += 1;
+                        } else if in_flight.is_empty() {
+                            break;
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(delay), if started < models.len() => {
+                    in_flight.push(start(started));
+                    started += 1;
+                }
+            }
+        }
+        errors.sort_by_key(|(index, _)| *index);
+        Err(errors.remove(0).1)
+    }
+
+    async fn within_timeout<T>(
+        &self,
+        request: impl Future<Output = CompletionResult<T>>,
+    ) -> CompletionResult<T> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request)
+                .await
+                .unwrap_or(Err(CompletionError::Timeout(timeout))),
+            None => request.await,
+        }
+    }
+}
@@ -4,6 +4,8 @@ use self::{
     parser::Parser,
 };
 use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
 use tracing_log::log::info;
 
@@ -13,7 +15,7 @@ mod parser;
 mod tests;
 mod tokens;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Function {
     pub name: String,
     pub description: String,
@@ -32,7 +34,69 @@ impl TryFrom<&str> for Function {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Controls which of the given functions a model is allowed to call
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ToolChoice {
+    /// The model decides whether to call any functions at all
+    #[default]
+    Auto,
+    /// The model must call at least one of the functions
+    Required,
+    /// The model must call the function with this name
+    Named(String),
+}
+
+/// A single function call made by a model. Models that support parallel tool calls may return
+/// several of these from one completion
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Provider assigned id of the call
+    pub id: String,
+    /// Name of the called function
+    pub name: String,
+    /// JSON object where the keys are the parameter identifiers
+    pub arguments: Value,
+}
+
+/// Serializes function parameters into the JSON schema object shared by every provider's
+/// function calling API
+pub(crate) fn serialize_params(params: &HashMap<String, FunctionParam>) -> Value {
+    let mut all_params = Map::new();
+    let mut req = vec![];
+    for (name, param) in params.iter() {
+        let mut current_param = Map::new();
+        match &param.typ {
+            ParamType::String => {
+                current_param.insert("type".to_owned(), "string".to_owned().into());
+            }
+            ParamType::Bool => {
+                current_param.insert("type".to_owned(), "boolean".to_owned().into());
+            }
+            ParamType::Integer => {
+                current_param.insert("type".to_owned(), "integer".to_owned().into());
+            }
+            ParamType::Enum(variants) => {
+                current_param.insert("type".to_owned(), "string".to_owned().into());
+                current_param.insert("enum".to_owned(), json!(variants));
+            }
+        }
+
+        if let Some(desc) = &param.description {
+            current_param.insert("description".to_owned(), desc.to_owned().into());
+        }
+        all_params.insert(name.to_owned(), json!(current_param));
+        if param.required {
+            req.push(name);
+        }
+    }
+    json!({
+        "type": "object",
+        "properties": all_params,
+        "required": json!(req),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FunctionParam {
     pub description: Option<String>,
     pub typ: ParamType,
@@ -70,7 +134,7 @@ impl FunctionParam {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParamType {
     String,
     Integer,
@@ -74,3 +74,65 @@ fn parses_to_function_correctly() {
         }
     }
 }
+
+#[test]
+fn correctly_serialize_params() {
+    let mut params = HashMap::new();
+    params.insert(
+        String::from("location"),
+        FunctionParam {
+            required: false,
+            typ: ParamType::String,
+            description: Some("the city and state, e.g. san francisco, ca".to_owned()),
+        },
+    );
+    params.insert(
+        String::from("format"),
+        FunctionParam {
+            required: true,
+            typ: ParamType::Enum(vec![String::from("celcius"), String::from("fahrenheight")]),
+            description: None,
+        },
+    );
+    params.insert(
+        String::from("num_days"),
+        FunctionParam {
+            required: true,
+            typ: ParamType::Integer,
+            description: Some("the number of days to forcast".to_owned()),
+        },
+    );
+
+    let expected = serde_json::json!({
+            "type": "object",
+            "properties": {
+              "location": {
+                "type": "string",
+                "description": "the city and state, e.g. san francisco, ca"
+              },
+                "num_days": {
+                "type": "integer",
+                "description": "the number of days to forcast",
+                },
+              "format": {
+                "type": "string",
+                "enum": ["celcius", "fahrenheight"]
+              }
+            },
+            "required": ["num_days", "format"]}
+    );
+
+    let serialized = serialize_params(&params);
+
+    for (k, v) in expected["properties"].as_object().unwrap().into_iter() {
+        assert_eq!(v, &serialized["properties"][k])
+    }
+    for r in expected["required"].as_array().unwrap() {
+        assert!(serialized["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|v| *v == r)
+            .is_some())
+    }
+}
@@ -1,22 +1,40 @@
 use super::{
     error::{CompletionError, CompletionResult},
-    functions::Function,
+    functions::{Function, ToolCall, ToolChoice},
     streaming::ProviderStreamHandler,
+    usage::TokenUsage,
     ModelParameters,
 };
 use crate::agents::memory::MessageStack;
+use anyhow::anyhow;
 use futures::Future;
 use reqwest::{header::HeaderMap, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{fmt::Debug, pin::Pin};
+use std::{fmt::Debug, pin::Pin, time::Duration};
 
+/// The extension point for completion providers.
+/// Anything implementing this trait can be wrapped in `CompletionProvider::Custom` and used
+/// with `Agent::io_completion`, `Agent::stream_completion` & `Agent::function_completion`
+/// exactly like the built in OpenAi & Anthropic providers.
+/// Only `model_str`, `url_str`, `serialize_messages` & `headers` are required.
+/// `into_io_req`, `into_stream_req`, `into_function_stream_req`, `serialize_tools` &
+/// `process_tools_response` return `CompletionError::FunctionNotImplemented` unless overridden.
+/// `serialize_function` & `process_function_response` are built on top of the tools methods, so
+/// implementing `serialize_tools` & `process_tools_response` enables both kinds of completion.
+/// `supports_n` defaults to false & `process_usage` to no usage.
 #[allow(unused)]
-pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
+pub trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
+    /// The model identifier sent to the provider
     fn model_str(&self) -> &str;
+    /// The endpoint every request is posted to
     fn url_str(&self) -> &str;
+    /// Turn a `MessageStack` into whatever the provider expects as its `messages` field
     fn serialize_messages(&self, stack: &MessageStack) -> Value;
+    /// Headers sent with every request, `api_key` is the key stored on the `CompletionModel`
     fn headers(&self, api_key: &str) -> HeaderMap;
+    /// Build a request for a single, non streamed completion.
+    /// The returned request's `process_response` should return `CompletionResponse::Io`
     fn into_io_req(
         &self,
         stack: &MessageStack,
@@ -24,6 +42,8 @@ pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
         Err(CompletionError::FunctionNotImplemented)
     }
+    /// Build a request for a streamed completion.
+    /// The returned request's `process_response` should return `CompletionResponse::Stream`
     fn into_stream_req(
         &self,
         stack: &MessageStack,
@@ -31,17 +51,60 @@ pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
         Err(CompletionError::FunctionNotImplemented)
     }
-    // currently only supports single functions, but functions can technically be added to models
-    // like messages
+    /// Build the JSON body of a request offering the model every function in `functions`
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        Err(CompletionError::FunctionNotImplemented)
+    }
+    /// Pull every tool call out of a tool completion response body
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<Vec<ToolCall>> {
+        Err(CompletionError::FunctionNotImplemented)
+    }
+    /// Build the JSON body of a function completion request, where the model is forced to call
+    /// `function`. Defaults to `serialize_tools` with `ToolChoice::Named`
     fn serialize_function(
         &self,
         stack: &MessageStack,
+        params: &ModelParameters,
         function: Function,
     ) -> CompletionResult<Value> {
+        let choice = ToolChoice::Named(function.name.to_owned());
+        self.serialize_tools(stack, params, std::slice::from_ref(&function), &choice)
+    }
+    /// Build a request for a streamed function completion, where the model is forced to call
+    /// `function` & its arguments are streamed as `StreamEvent::ToolCallDelta`s.
+    /// The returned request's `process_response` should return `CompletionResponse::Stream`
+    fn into_function_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        function: Function,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
         Err(CompletionError::FunctionNotImplemented)
     }
+    /// Pull the JSON arguments object out of a function completion response body.
+    /// Defaults to the arguments of the first call returned by `process_tools_response`
     fn process_function_response(&self, response_json: Value) -> CompletionResult<Value> {
-        Err(CompletionError::FunctionNotImplemented)
+        self.process_tools_response(response_json)?
+            .into_iter()
+            .next()
+            .map(|call| call.arguments)
+            .ok_or(CompletionError::from(anyhow!("No tool calls in response")))
+    }
+    /// Whether a single request returns `ModelParameters::n` choices.
+    /// When false, `Agent::multi_choice_completion` sends `n` concurrent requests instead
+    fn supports_n(&self) -> bool {
+        false
+    }
+    /// Pull the reported token usage out of a tool or function completion response body.
+    /// Defaults to `None`, meaning the provider reports no usage
+    fn process_usage(&self, response_json: &Value) -> Option<TokenUsage> {
+        None
     }
 }
 
@@ -50,14 +113,14 @@ pub type ProcessResponseReturn<'r> =
 pub trait CompletionRequest: Debug + Sync + Send + 'static {
     // We can't put Serialize and Deserialize as trait bounds, so we have `as_json`
     fn as_json(&self) -> CompletionResult<Value>;
-    fn process_response<'r>(&'r self, response: Response) -> ProcessResponseReturn;
+    fn process_response(&self, response: Response) -> ProcessResponseReturn<'_>;
 }
 
 /// Any possible response from an inference endpoint
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CompletionResponse {
     /// For IO completions
-    Io(String),
+    Io(IoCompletion),
     /// For streamed completions
     #[serde(skip)]
     Stream(ProviderStreamHandler),
@@ -65,9 +128,111 @@ pub enum CompletionResponse {
     Function(Value),
 }
 
+/// Why a model stopped generating, normalized across providers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinishReason {
+    /// The model finished its message. OpenAi also reports hitting a stop sequence as this
+    Stop,
+    /// The model generated one of `ModelParameters::stop`
+    StopSequence,
+    /// Output was cut off by `ModelParameters::max_tokens` or the context length
+    Length,
+    /// The model stopped to call tools
+    ToolCalls,
+    /// Output was omitted by the provider's content filter
+    ContentFilter,
+    /// Any reason not listed above, as the provider reported it
+    Other(String),
+}
+
+impl From<&str> for FinishReason {
+    fn from(value: &str) -> Self {
+        match value {
+            "stop" | "end_turn" => Self::Stop,
+            "stop_sequence" => Self::StopSequence,
+            "length" | "max_tokens" => Self::Length,
+            "tool_calls" | "function_call" | "tool_use" => Self::ToolCalls,
+            "content_filter" => Self::ContentFilter,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+}
+
+/// One candidate message of an IO completion
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompletionChoice {
+    pub content: String,
+    /// `None` if the provider did not say
+    pub finish_reason: Option<FinishReason>,
+}
+
+impl CompletionChoice {
+    pub fn new(content: String, finish_reason: Option<&str>) -> Self {
+        Self {
+            content,
+            finish_reason: finish_reason.map(FinishReason::from),
+        }
+    }
+}
+
+/// A finished IO completion along with everything the provider reported about it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IoCompletion {
+    /// Every candidate message, there is only more than one when `ModelParameters::n` > 1
+    pub choices: Vec<CompletionChoice>,
+    /// The model that served the request, which may be more specific than the requested one
+    pub model: Option<String>,
+    /// The id the provider assigned to the request, useful when contacting their support
+    pub request_id: Option<String>,
+    /// `None` if the provider did not report any
+    pub usage: Option<TokenUsage>,
+    /// Time from sending the request until the response was processed
+    pub latency: Duration,
+}
+
+impl IoCompletion {
+    pub fn new(choices: Vec<CompletionChoice>, usage: Option<TokenUsage>) -> Self {
+        Self {
+            choices,
+            model: None,
+            request_id: None,
+            usage,
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// Content of the first choice
+    pub fn content(&self) -> Option<&str> {
+        self.choices.first().map(|c| c.content.as_str())
+    }
+
+    /// Finish reason of the first choice
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.choices.first().and_then(|c| c.finish_reason.as_ref())
+    }
+}
+
+impl CompletionResponse {
+    /// A single choice IO completion along with the token usage reported by the provider
+    pub fn io(content: String, usage: Option<TokenUsage>) -> Self {
+        IoCompletion::new(vec![CompletionChoice::new(content, None)], usage).into()
+    }
+}
+
+impl From<IoCompletion> for CompletionResponse {
+    fn from(value: IoCompletion) -> Self {
+        Self::Io(value)
+    }
+}
+
 impl From<String> for CompletionResponse {
     fn from(value: String) -> Self {
-        Self::Io(value)
+        Self::io(value, None)
     }
 }
 
@@ -86,8 +251,10 @@ impl From<Value> for CompletionResponse {
 impl TryInto<String> for CompletionResponse {
     type Error = CompletionError;
     fn try_into(self) -> Result<String, Self::Error> {
-        if let Self::Io(s) = self {
-            return Ok(s);
+        if let Self::Io(completion) = self {
+            if let Some(choice) = completion.choices.into_iter().next() {
+                return Ok(choice.content);
+            }
         }
         Err(CompletionError::CouldNotCoerce)
     }
@@ -1,31 +1,80 @@
 pub mod anthropic;
 pub mod error;
+pub mod fallback;
 pub mod functions;
 #[cfg(feature = "bert")]
 pub mod huggingface;
-mod inference;
+pub mod inference;
+pub mod ollama;
 pub mod openai;
 pub mod streaming;
+pub mod usage;
 use self::{
-    anthropic::builder::AnthropicCompletionModel, error::CompletionResult, functions::Function,
-    inference::CompletionRequestBuilder, openai::builder::OpenAiCompletionModel,
+    anthropic::builder::AnthropicCompletionModel,
+    error::{CompletionError, CompletionResult},
+    fallback::{FallbackMode, FallbackResult},
+    functions::{Function, ToolCall, ToolChoice},
+    inference::{CompletionRequestBuilder, CompletionResponse, IoCompletion},
+    ollama::builder::OllamaCompletionModel,
+    openai::{builder::OpenAiCompletionModel, compatible::OpenAiCompatibleModel},
     streaming::ProviderStreamHandler,
+    usage::TokenUsage,
 };
 
-use crate::agents::memory::MessageStack;
-use anyhow::anyhow;
-use reqwest::Client;
+use crate::{
+    agents::memory::MessageStack,
+    language_models::{
+        cache::ResponseCache,
+        credentials::{CredentialProvider, Credentials, EnvVarKey},
+        rate_limit::{estimate_tokens, RateLimit, Throttle},
+        retry::RetryPolicy,
+        secret::{RedactedHeaders, SecretString},
+        transport::default_client,
+    },
+};
+use futures::future::try_join_all;
+use reqwest::{header::HeaderMap, Client};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::fmt::Debug;
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tracing::{info, warn};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompletionProvider {
     OpenAi(OpenAiCompletionModel),
     Anthropic(AnthropicCompletionModel),
+    /// Self hosted or third party servers that speak OpenAi's chat completions protocol
+    OpenAiCompatible(OpenAiCompatibleModel),
+    /// Models served by a local Ollama instance
+    Ollama(OllamaCompletionModel),
+    /// Any third party provider implementing `CompletionRequestBuilder`.
+    /// Custom providers cannot be serialized, an `Agent` using one will fail to serialize
+    #[serde(skip)]
+    Custom(Arc<dyn CompletionRequestBuilder>),
+}
+
+impl PartialEq for CompletionProvider {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::OpenAi(a), Self::OpenAi(b)) => a == b,
+            (Self::Anthropic(a), Self::Anthropic(b)) => a == b,
+            (Self::OpenAiCompatible(a), Self::OpenAiCompatible(b)) => a == b,
+            (Self::Ollama(a), Self::Ollama(b)) => a == b,
+            (Self::Custom(a), Self::Custom(b)) => {
+                Arc::ptr_eq(a, b) || (a.model_str() == b.model_str() && a.url_str() == b.url_str())
+            }
+            _ => false,
+        }
+    }
 }
 
+impl Eq for CompletionProvider {}
+
 impl From<OpenAiCompletionModel> for CompletionProvider {
     fn from(value: OpenAiCompletionModel) -> Self {
         Self::OpenAi(value)
@@ -38,11 +87,46 @@ impl From<AnthropicCompletionModel> for CompletionProvider {
     }
 }
 
+impl From<OpenAiCompatibleModel> for CompletionProvider {
+    fn from(value: OpenAiCompatibleModel) -> Self {
+        Self::OpenAiCompatible(value)
+    }
+}
+
+impl From<OllamaCompletionModel> for CompletionProvider {
+    fn from(value: OllamaCompletionModel) -> Self {
+        Self::Ollama(value)
+    }
+}
+
+impl<T: CompletionRequestBuilder> From<Arc<T>> for CompletionProvider {
+    fn from(value: Arc<T>) -> Self {
+        Self::Custom(value)
+    }
+}
+
 impl CompletionProvider {
-    fn inner_builder(&self) -> Box<&dyn CompletionRequestBuilder> {
+    /// Environment variable `CompletionModel::from_env` reads this provider's api key from
+    pub fn api_key_var(&self) -> Option<&'static str> {
+        match self {
+            Self::OpenAi(_) => Some("OPENAI_KEY"),
+            Self::Anthropic(_) => Some("ANTHROPIC_KEY"),
+            _ => None,
+        }
+    }
+
+    /// Wrap any `CompletionRequestBuilder` implementor as a provider
+    pub fn custom(builder: impl CompletionRequestBuilder) -> Self {
+        Self::Custom(Arc::new(builder))
+    }
+
+    fn inner_builder(&self) -> &dyn CompletionRequestBuilder {
         match &self {
-            Self::OpenAi(b) => return Box::new(b),
-            Self::Anthropic(b) => return Box::new(b),
+            Self::OpenAi(b) => b,
+            Self::Anthropic(b) => b,
+            Self::OpenAiCompatible(b) => b,
+            Self::Ollama(b) => b,
+            Self::Custom(b) => b.as_ref(),
         }
     }
 }
@@ -51,34 +135,71 @@ impl CompletionProvider {
 pub struct CompletionModel {
     pub provider: CompletionProvider,
     pub params: ModelParameters,
-    pub api_key: String,
+    /// Where the api key comes from, resolved before every request. Never serialized, supply it
+    /// again after deserializing with `supply_api_keys`
     #[serde(skip)]
+    pub credentials: Credentials,
+    /// How failed requests are retried, see `RetryPolicy`
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// Client side rate limit shared by every model using the same api key, see `RateLimit`
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Longest a completion may take, or a stream may take to send its first chunk. Once elapsed the
+    /// next fallback is tried
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+    /// Models tried in order when this one fails with a retryable error or times out
+    #[serde(default)]
+    pub fallbacks: Vec<CompletionModel>,
+    /// Whether fallbacks are tried one after another or raced, see `FallbackMode`
+    #[serde(default)]
+    pub fallback_mode: FallbackMode,
+    /// Cache of io, function & tool responses, not serialized so it must be set again after
+    /// deserializing
+    #[serde(skip)]
+    pub cache: Option<ResponseCache>,
+    /// Not serialized, a deserialized model is given the `default_client`
+    #[serde(skip, default = "default_client")]
     client: Client,
 }
 
-impl Eq for CompletionModel {}
-
 impl PartialEq for CompletionModel {
     fn eq(&self, other: &Self) -> bool {
         self.provider == other.provider
             && self.params == other.params
-            && self.api_key == other.api_key
+            && self.retry_policy == other.retry_policy
+            && self.rate_limit == other.rate_limit
+            && self.timeout == other.timeout
+            && self.fallbacks == other.fallbacks
+            && self.fallback_mode == other.fallback_mode
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// Sampling parameters sent with every completion. Any parameter left `None` is not sent, so the
+/// provider's default is used. Setting a parameter the provider does not support makes the
+/// request fail with `CompletionError::UnsupportedParameter` before anything is sent
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ModelParameters {
-    /// Total token usage count of the model
-    pub total_token_count: u32,
-    /// What sampling temperature to use, between 0 and 2.
+    /// What sampling temperature to use, between 0 and 2 (0 and 1 for Anthropic).
     /// Higher values like 0.8 will make the output more random,
     /// while lower values like 0.2 will make it more focused and deterministic.
-    /// Input as a value between 0 and 200. Will be turned into float.
-    pub temperature: Option<u8>,
+    #[serde(default, deserialize_with = "deserialize_temperature")]
+    pub temperature: Option<f32>,
+    /// Nucleus sampling, only the tokens comprising the top `top_p` probability mass are considered.
+    /// Generally alter this or `temperature` but not both
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Only sample from the `top_k` most likely tokens. Not supported by OpenAi
+    #[serde(default)]
+    pub top_k: Option<u32>,
     /// Number between -2.0 and 2.0.
     /// Positive values penalize new tokens based on their existing frequency in the text so far,
-    /// decreasing the model's likelihood to repeat the same line verbatim.
-    pub frequency_penalty: Option<i8>,
+    /// decreasing the model's likelihood to repeat the same line verbatim. Not supported by Anthropic
+    pub frequency_penalty: Option<f32>,
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far,
+    /// increasing the model's likelihood to talk about new topics. Not supported by Anthropic
+    pub presence_penalty: Option<f32>,
     /// The maximum number of tokens that can be generated in the chat completion.
     /// The total length of input tokens and generated tokens is limited by the model's context length.
     pub max_tokens: Option<u32>,
@@ -86,28 +207,86 @@ pub struct ModelParameters {
     /// Note that you will be charged based on the number of generated tokens across all of the choices.
     /// Keep n as 1 to minimize costs.
     pub n: Option<u32>,
-    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far,
-    /// increasing the model's likelihood to talk about new topics.
-    pub presence_penalty: Option<i8>,
+    /// Sequences where the model will stop generating further tokens
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    /// Makes sampling as deterministic as the provider allows. Not supported by Anthropic
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Maps token ids to a bias between -100 and 100 added to that token's logits.
+    /// Only supported by OpenAi & OpenAi compatible providers
+    #[serde(default)]
+    pub logit_bias: Option<BTreeMap<u32, i32>>,
+}
+
+/// Temperatures used to be saved as a percent between 0 & 200, integers above 2 are read as one
+fn deserialize_temperature<'de, D>(deserializer: D) -> Result<Option<f32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(
+        Option::<serde_json::Number>::deserialize(deserializer)?.map(|number| {
+            match number.as_u64() {
+                Some(percent) if percent > 2 => percent as f32 / 100.0,
+                _ => number.as_f64().unwrap_or_default() as f32,
+            }
+        }),
+    )
 }
 
 impl Default for ModelParameters {
     fn default() -> Self {
         Self {
-            total_token_count: 0,
-            temperature: Some(70),
+            temperature: Some(0.7),
+            top_p: None,
+            top_k: None,
             frequency_penalty: None,
+            presence_penalty: None,
             max_tokens: None,
             n: Some(1),
-            presence_penalty: None,
+            stop: None,
+            seed: None,
+            logit_bias: None,
         }
     }
 }
 
 impl ModelParameters {
-    /// Temperature is computed to a number between 0 and 1 by dividing this value by 100
-    fn temperature(&self) -> Result<f32, anyhow::Error> {
-        Ok((self.temperature.ok_or(anyhow!("No temperature"))? / 100) as f32)
+    /// Names of every optional parameter that has been set
+    fn set_parameters(&self) -> Vec<&'static str> {
+        let set = [
+            ("temperature", self.temperature.is_some()),
+            ("top_p", self.top_p.is_some()),
+            ("top_k", self.top_k.is_some()),
+            ("frequency_penalty", self.frequency_penalty.is_some()),
+            ("presence_penalty", self.presence_penalty.is_some()),
+            ("max_tokens", self.max_tokens.is_some()),
+            ("stop", self.stop.is_some()),
+            ("seed", self.seed.is_some()),
+            ("logit_bias", self.logit_bias.is_some()),
+        ];
+        set.into_iter()
+            .filter_map(|(name, is_set)| is_set.then_some(name))
+            .collect()
+    }
+
+    /// Errors if any of the `unsupported` parameters has been set
+    pub(crate) fn ensure_supported(
+        &self,
+        provider: &'static str,
+        unsupported: &[&'static str],
+    ) -> CompletionResult<()> {
+        match self
+            .set_parameters()
+            .into_iter()
+            .find(|p| unsupported.contains(p))
+        {
+            Some(parameter) => Err(CompletionError::UnsupportedParameter {
+                provider,
+                parameter,
+            }),
+            None => Ok(()),
+        }
     }
 }
 
@@ -117,23 +296,35 @@ impl CompletionModel {
         params: ModelParameters,
         api_key: &str,
     ) -> CompletionModel {
-        let client = Client::new();
+        let client = default_client();
         Self {
             provider: m.into(),
             params,
             client,
-            api_key: api_key.to_owned(),
+            credentials: api_key.into(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            timeout: None,
+            fallbacks: vec![],
+            fallback_mode: FallbackMode::default(),
+            cache: None,
         }
     }
 
     ///  openai gpt3 handler with 0.7 temp
     pub fn default_openai(api_key: &str) -> CompletionModel {
         let provider = CompletionProvider::OpenAi(OpenAiCompletionModel::default());
-        let client = reqwest::Client::new();
+        let client = default_client();
         CompletionModel {
             provider,
             params: ModelParameters::default(),
-            api_key: api_key.to_owned(),
+            credentials: api_key.into(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            timeout: None,
+            fallbacks: vec![],
+            fallback_mode: FallbackMode::default(),
+            cache: None,
             client,
         }
     }
@@ -141,40 +332,310 @@ impl CompletionModel {
     ///  anthropic Haiku handler with 0.7 temp
     pub fn default_anthropic(api_key: &str) -> CompletionModel {
         let provider = CompletionProvider::Anthropic(AnthropicCompletionModel::default());
-        let client = reqwest::Client::new();
+        let client = default_client();
+        CompletionModel {
+            provider,
+            params: ModelParameters::default(),
+            credentials: api_key.into(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            timeout: None,
+            fallbacks: vec![],
+            fallback_mode: FallbackMode::default(),
+            cache: None,
+            client,
+        }
+    }
+
+    ///  local Ollama handler for the given model tag with 0.7 temp, Ollama needs no api key
+    pub fn default_ollama(model: &str) -> CompletionModel {
+        let provider = CompletionProvider::Ollama(OllamaCompletionModel::new(model));
+        let client = default_client();
         CompletionModel {
             provider,
             params: ModelParameters::default(),
-            api_key: api_key.to_owned(),
+            credentials: Credentials::default(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            timeout: None,
+            fallbacks: vec![],
+            fallback_mode: FallbackMode::default(),
+            cache: None,
             client,
         }
     }
 
+    /// Model whose api key is read from the environment before every request, `OPENAI_KEY` for
+    /// OpenAi & `ANTHROPIC_KEY` for Anthropic. Other providers are given no key
+    pub fn from_env(m: impl Into<CompletionProvider>, params: ModelParameters) -> CompletionModel {
+        let model = Self::new(m, params, "");
+        match model.provider.api_key_var() {
+            Some(var) => model.with_credentials(EnvVarKey::new(var)),
+            None => model,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: impl Into<SecretString>) -> Self {
+        self.credentials = Credentials::new(api_key.into());
+        self
+    }
+
+    /// Resolve the api key from `credentials` before every request, see `CredentialProvider`
+    pub fn with_credentials(mut self, credentials: impl CredentialProvider + 'static) -> Self {
+        self.credentials = Credentials::new(credentials);
+        self
+    }
+
+    /// Sets the api key of this model & each of its fallbacks to the key `keys` returns for its
+    /// provider, keys are left as they are where `keys` returns `None`.
+    /// Api keys are not serialized, so this is needed after deserializing a model
+    pub fn supply_api_keys(&mut self, keys: impl Fn(&CompletionProvider) -> Option<SecretString>) {
+        self.supply_api_keys_with(&keys);
+    }
+
+    fn supply_api_keys_with(&mut self, keys: &dyn Fn(&CompletionProvider) -> Option<SecretString>) {
+        if let Some(key) = keys(&self.provider) {
+            self.credentials = Credentials::new(key);
+        }
+        for fallback in self.fallbacks.iter_mut() {
+            fallback.supply_api_keys_with(keys);
+        }
+    }
+
+    /// Send requests with `client` instead of the `default_client`, see `TransportConfig`
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.set_client(client);
+        self
+    }
+
+    /// Sets the client of this model & each of its fallbacks
+    pub fn set_client(&mut self, client: Client) {
+        for fallback in self.fallbacks.iter_mut() {
+            fallback.set_client(client.clone());
+        }
+        self.client = client;
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Add a model to try after this one and any fallbacks added before it
+    pub fn with_fallback(mut self, fallback: CompletionModel) -> Self {
+        self.fallbacks.push(fallback);
+        self
+    }
+
+    pub fn with_fallback_mode(mut self, fallback_mode: FallbackMode) -> Self {
+        self.fallback_mode = fallback_mode;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// The cache & key of a request, if the model has a cache
+    fn cache_key(&self, url: &str, request: &Value) -> Option<(&ResponseCache, String)> {
+        let cache = self.cache.as_ref()?;
+        Some((cache, ResponseCache::key(url, request)))
+    }
+
+    /// The rate limit a request is sent under, if the model has one
+    fn throttle<'t>(
+        &'t self,
+        url: &'t str,
+        api_key: &'t SecretString,
+        body: &impl Serialize,
+        max_tokens: Option<u32>,
+    ) -> Option<Throttle<'t>> {
+        Some(Throttle {
+            limit: self.rate_limit.as_ref()?,
+            url,
+            api_key: api_key.expose(),
+            tokens: estimate_tokens(body, max_tokens),
+        })
+    }
+
+    /// The model identifier sent to the provider
+    pub fn model_str(&self) -> &str {
+        self.provider.inner_builder().model_str()
+    }
+
     #[tracing::instrument(name = "io completion", skip_all)]
     pub(crate) async fn get_io_completion(
         &self,
         messages: &MessageStack,
-    ) -> CompletionResult<String> {
+        read_cache: bool,
+    ) -> FallbackResult<IoCompletion> {
+        self.try_chain(|model| model.io_request(messages, &model.params, read_cache))
+            .await
+    }
+
+    #[tracing::instrument(name = "multi choice completion", skip(self, messages))]
+    pub(crate) async fn get_io_choices(
+        &self,
+        messages: &MessageStack,
+        n: u32,
+        read_cache: bool,
+    ) -> FallbackResult<IoCompletion> {
+        self.try_chain(|model| model.choices_request(messages, n, read_cache))
+            .await
+    }
+
+    /// Falls back only until a stream has sent its first chunk
+    #[tracing::instrument(name = "streamed completion", skip_all)]
+    pub(crate) async fn get_stream_completion(
+        &self,
+        messages: &MessageStack,
+    ) -> FallbackResult<ProviderStreamHandler> {
+        self.try_chain(|model| model.stream_request(messages, None))
+            .await
+    }
+
+    /// Falls back only until a stream has sent its first chunk
+    #[tracing::instrument(name = "streamed function completion", skip_all)]
+    pub(crate) async fn get_stream_fn_completion(
+        &self,
+        messages: &MessageStack,
+        function: Function,
+    ) -> FallbackResult<ProviderStreamHandler> {
+        self.try_chain(|model| model.stream_request(messages, Some(&function)))
+            .await
+    }
+
+    #[tracing::instrument(name = "tool completion", skip_all)]
+    pub(crate) async fn get_tool_completion(
+        &self,
+        messages: &MessageStack,
+        functions: &[Function],
+        choice: &ToolChoice,
+        read_cache: bool,
+    ) -> FallbackResult<(Vec<ToolCall>, Option<TokenUsage>)> {
+        self.try_chain(|model| model.tool_request(messages, functions, choice, read_cache))
+            .await
+    }
+
+    #[tracing::instrument(name = "function completion", skip_all)]
+    pub(crate) async fn get_fn_completion(
+        &self,
+        messages: &MessageStack,
+        function: Function,
+        read_cache: bool,
+    ) -> FallbackResult<(Value, Option<TokenUsage>)> {
+        self.try_chain(|model| model.fn_request(messages, &function, read_cache))
+            .await
+    }
+
+    /// Gets `n` choices in one request when the provider supports it, otherwise sends `n`
+    /// concurrent single choice requests. Usage of every request is summed, the model & request id
+    /// are those of the first request and latency is that of the slowest.
+    /// Concurrent single choice requests are identical, so they never read the cache
+    async fn choices_request(
+        &self,
+        messages: &MessageStack,
+        n: u32,
+        read_cache: bool,
+    ) -> CompletionResult<IoCompletion> {
+        if self.provider.inner_builder().supports_n() {
+            let params = ModelParameters {
+                n: Some(n),
+                ..self.params.clone()
+            };
+            return self.io_request(messages, &params, read_cache).await;
+        }
+        let params = ModelParameters {
+            n: Some(1),
+            ..self.params.clone()
+        };
+        let mut responses = try_join_all((0..n).map(|_| self.io_request(messages, &params, false)))
+            .await?
+            .into_iter();
+
+        let mut completion = responses
+            .next()
+            .unwrap_or_else(|| IoCompletion::new(vec![], None));
+        for response in responses {
+            completion.choices.extend(response.choices);
+            if let Some(u) = response.usage {
+                *completion.usage.get_or_insert_with(TokenUsage::default) += u;
+            }
+            completion.latency = completion.latency.max(response.latency);
+        }
+        Ok(completion)
+    }
+
+    async fn io_request(
+        &self,
+        messages: &MessageStack,
+        params: &ModelParameters,
+        read_cache: bool,
+    ) -> CompletionResult<IoCompletion> {
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
+        let api_key = self.credentials.resolve()?;
+        let headers = builder.headers(api_key.expose());
         let url = builder.url_str();
-        let req = builder.into_io_req(messages, &self.params)?;
+        let req = builder.into_io_req(messages, params)?;
         let json_req = req.as_json()?;
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
-            json_req, url, headers
+            json_req,
+            url,
+            RedactedHeaders(&headers)
         );
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .json(&json_req)
-            .send()
+        let cached = self.cache_key(url, &json_req);
+        if let Some((cache, key)) = cached.as_ref().filter(|_| read_cache) {
+            if let Some(completion) = cache.get::<IoCompletion>(key) {
+                return Ok(IoCompletion {
+                    usage: None,
+                    latency: Duration::ZERO,
+                    ..completion
+                });
+            }
+        }
+
+        let throttle = self.throttle(url, &api_key, &json_req, params.max_tokens);
+        let start = Instant::now();
+        let (response, permit) = self
+            .retry_policy
+            .send(
+                || {
+                    self.client
+                        .post(url)
+                        .headers(headers.clone())
+                        .json(&json_req)
+                },
+                throttle.as_ref(),
+            )
             .await?;
+        let request_id = request_id(response.headers());
 
         match req.process_response(response).await {
-            Ok(r) => return Ok(TryInto::<String>::try_into(r)?),
+            Ok(CompletionResponse::Io(mut completion)) => {
+                completion.latency = start.elapsed();
+                if let Some(permit) = permit {
+                    permit.settle(completion.usage);
+                }
+                completion.request_id = completion.request_id.or(request_id);
+                if let Some((cache, key)) = cached {
+                    cache.insert(&key, &completion);
+                }
+                Ok(completion)
+            }
+            Ok(_) => Err(CompletionError::CouldNotCoerce),
             Err(err) => {
                 warn!("Error getting Io completion: {:?}", err);
                 Err(err)
@@ -182,31 +643,52 @@ impl CompletionModel {
         }
     }
 
-    #[tracing::instrument(name = "streamed completion", skip_all)]
-    pub(crate) async fn get_stream_completion(
+    /// Streams the arguments of a call to `function` if given one, otherwise a message
+    async fn stream_request(
         &self,
         messages: &MessageStack,
+        function: Option<&Function>,
     ) -> CompletionResult<ProviderStreamHandler> {
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
+        let api_key = self.credentials.resolve()?;
+        let headers = builder.headers(api_key.expose());
         let url = builder.url_str();
-        let req = builder.into_stream_req(messages, &self.params)?;
+        let req = match function {
+            Some(function) => {
+                builder.into_function_stream_req(messages, &self.params, function.clone())?
+            }
+            None => builder.into_stream_req(messages, &self.params)?,
+        };
         let json_req = req.as_json()?;
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
-            json_req, url, headers
+            json_req,
+            url,
+            RedactedHeaders(&headers)
         );
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .json(&json_req)
-            .send()
+        let throttle = self.throttle(url, &api_key, &json_req, self.params.max_tokens);
+        let (response, permit) = self
+            .retry_policy
+            .send(
+                || {
+                    self.client
+                        .post(url)
+                        .headers(headers.clone())
+                        .json(&json_req)
+                },
+                throttle.as_ref(),
+            )
             .await?;
 
         match req.process_response(response).await {
-            Ok(r) => return Ok(TryInto::<ProviderStreamHandler>::try_into(r)?),
+            Ok(r) => {
+                let mut handler = TryInto::<ProviderStreamHandler>::try_into(r)?;
+                // Streamed usage arrives after this returns, the handler settles once it has it
+                handler.set_permit(permit);
+                handler.first_chunk().await?;
+                Ok(handler)
+            }
             Err(err) => {
                 warn!("Error getting streamed Io completion: {:?}", err);
                 Err(err.into())
@@ -214,32 +696,106 @@ impl CompletionModel {
         }
     }
 
-    #[tracing::instrument(name = "function completion", skip_all)]
-    pub(crate) async fn get_fn_completion(
+    async fn tool_request(
         &self,
         messages: &MessageStack,
-        function: Function,
-    ) -> CompletionResult<Value> {
+        functions: &[Function],
+        choice: &ToolChoice,
+        read_cache: bool,
+    ) -> CompletionResult<(Vec<ToolCall>, Option<TokenUsage>)> {
+        let builder = self.provider.inner_builder();
+        let api_key = self.credentials.resolve()?;
+        let headers = builder.headers(api_key.expose());
+        let url = builder.url_str();
+        let req = builder.serialize_tools(messages, &self.params, functions, choice)?;
+        info!(
+            "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
+            req,
+            url,
+            RedactedHeaders(&headers)
+        );
+
+        let cached = self.cache_key(url, &req);
+        if let Some((cache, key)) = cached.as_ref().filter(|_| read_cache) {
+            if let Some(calls) = cache.get::<Vec<ToolCall>>(key) {
+                return Ok((calls, None));
+            }
+        }
+
+        let throttle = self.throttle(url, &api_key, &req, self.params.max_tokens);
+        let (response, permit) = self
+            .retry_policy
+            .send(
+                || self.client.post(url).headers(headers.clone()).json(&req),
+                throttle.as_ref(),
+            )
+            .await?;
+        let json = response.json().await?;
+        info!("Got response: {json:#?}");
+        let usage = builder.process_usage(&json);
+        if let Some(permit) = permit {
+            permit.settle(usage);
+        }
+        match builder.process_tools_response(json) {
+            Ok(r) => {
+                if let Some((cache, key)) = cached {
+                    cache.insert(&key, &r);
+                }
+                Ok((r, usage))
+            }
+            Err(err) => {
+                warn!("Error getting tool completion: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    async fn fn_request(
+        &self,
+        messages: &MessageStack,
+        function: &Function,
+        read_cache: bool,
+    ) -> CompletionResult<(Value, Option<TokenUsage>)> {
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
+        let api_key = self.credentials.resolve()?;
+        let headers = builder.headers(api_key.expose());
         let url = builder.url_str();
-        let req = builder.serialize_function(messages, function)?;
+        let req = builder.serialize_function(messages, &self.params, function.clone())?;
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
-            req, url, headers
+            req,
+            url,
+            RedactedHeaders(&headers)
         );
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .json(&req)
-            .send()
+        let cached = self.cache_key(url, &req);
+        if let Some((cache, key)) = cached.as_ref().filter(|_| read_cache) {
+            if let Some(json) = cache.get::<Value>(key) {
+                return Ok((json, None));
+            }
+        }
+
+        let throttle = self.throttle(url, &api_key, &req, self.params.max_tokens);
+        let (response, permit) = self
+            .retry_policy
+            .send(
+                || self.client.post(url).headers(headers.clone()).json(&req),
+                throttle.as_ref(),
+            )
             .await?;
         let json = response.json().await?;
         info!("Got response: {json:#?}");
+        let usage = builder.process_usage(&json);
+        if let Some(permit) = permit {
+            permit.settle(usage);
+        }
         match builder.process_function_response(json) {
-            Ok(r) => return Ok(r),
+            Ok(r) => {
+                if let Some((cache, key)) = cached {
+                    cache.insert(&key, &r);
+                }
+                Ok((r, usage))
+            }
             Err(err) => {
                 warn!("Error getting function completion: {:?}", err);
                 Err(err.into())
@@ -247,3 +803,11 @@ impl CompletionModel {
         }
     }
 }
+
+/// OpenAi sends the request id as `x-request-id`, Anthropic as `request-id`
+fn request_id(headers: &HeaderMap) -> Option<String> {
+    ["x-request-id", "request-id"]
+        .into_iter()
+        .find_map(|name| headers.get(name)?.to_str().ok())
+        .map(str::to_owned)
+}
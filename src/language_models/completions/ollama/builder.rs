@@ -0,0 +1,83 @@
+use super::{
+    super::{
+        error::CompletionResult,
+        inference::{CompletionRequest, CompletionRequestBuilder},
+        ModelParameters,
+    },
+    requests::OllamaIoRequest,
+};
+use crate::agents::memory::MessageStack;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const DEFAULT_OLLAMA_HOST: &str = "http://localhost:11434";
+
+/// `ModelParameters` Ollama has no equivalent for
+const UNSUPPORTED_PARAMS: &[&str] = &["logit_bias"];
+
+/// A model served by a local Ollama instance through its `/api/chat` endpoint
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OllamaCompletionModel {
+    url: String,
+    model: String,
+}
+
+impl OllamaCompletionModel {
+    /// `model` is any model tag pulled into Ollama, for example `llama3` or `mistral:7b`.
+    /// Expects Ollama to be running on it's default host
+    pub fn new(model: &str) -> Self {
+        Self::with_host(DEFAULT_OLLAMA_HOST, model)
+    }
+
+    /// For Ollama instances that are not running on `DEFAULT_OLLAMA_HOST`
+    pub fn with_host(host: &str, model: &str) -> Self {
+        Self {
+            url: format!("{}/api/chat", host.trim_end_matches('/')),
+            model: model.to_owned(),
+        }
+    }
+}
+
+impl CompletionRequestBuilder for OllamaCompletionModel {
+    fn model_str(&self) -> &str {
+        &self.model
+    }
+
+    fn url_str(&self) -> &str {
+        &self.url
+    }
+
+    fn headers(&self, _api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("Content-Type", "application/json".parse().unwrap());
+        map
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        stack
+            .as_ref()
+            .iter()
+            .map(|m| m.clone().into())
+            .collect::<Vec<Value>>()
+            .into()
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        params.ensure_supported("Ollama", UNSUPPORTED_PARAMS)?;
+        Ok(Box::new(OllamaIoRequest::new(stack, params, self, false)))
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        params.ensure_supported("Ollama", UNSUPPORTED_PARAMS)?;
+        Ok(Box::new(OllamaIoRequest::new(stack, params, self, true)))
+    }
+}
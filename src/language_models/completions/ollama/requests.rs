@@ -0,0 +1,216 @@
+use super::{builder::OllamaCompletionModel, streaming::OllamaStreamResponse};
+use crate::{
+    agents::memory::MessageStack,
+    language_models::completions::{
+        error::{CompletionResult, ProviderResponseError},
+        inference::{
+            CompletionChoice, CompletionRequest, CompletionRequestBuilder, IoCompletion,
+            ProcessResponseReturn,
+        },
+        streaming::{CompletionStream, ProviderStreamHandler, StreamedCompletionHandler},
+        usage::TokenUsage,
+        ModelParameters,
+    },
+};
+use futures::TryStreamExt;
+use reqwest::Response;
+use reqwest_streams::JsonStreamResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct OllamaIoRequest {
+    pub model: String,
+    pub messages: Value,
+    pub stream: bool,
+    pub options: OllamaOptions,
+}
+
+/// Subset of Ollama's model options that map onto `ModelParameters`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl OllamaIoRequest {
+    pub fn new(
+        stack: &MessageStack,
+        params: &ModelParameters,
+        typ: &OllamaCompletionModel,
+        stream: bool,
+    ) -> Self {
+        Self {
+            model: typ.model_str().to_string(),
+            messages: typ.serialize_messages(stack),
+            stream,
+            options: OllamaOptions {
+                temperature: params.temperature,
+                top_p: params.top_p,
+                top_k: params.top_k,
+                frequency_penalty: params.frequency_penalty,
+                presence_penalty: params.presence_penalty,
+                num_predict: params.max_tokens,
+                stop: params.stop.clone(),
+                seed: params.seed,
+            },
+        }
+    }
+}
+
+impl CompletionRequest for OllamaIoRequest {
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    fn process_response(&self, response: Response) -> ProcessResponseReturn<'_> {
+        Box::pin(async move {
+            match self.stream {
+                false => {
+                    let json: Value = response.json().await?;
+                    tracing::warn!("got response:  {json:#?}");
+                    let response: OllamaResponse = serde_json::from_value(json.clone())?;
+                    match response {
+                        OllamaResponse::Success(suc) => {
+                            let usage = suc.usage();
+                            let choice = CompletionChoice::new(
+                                suc.message.content,
+                                suc.done_reason.as_deref(),
+                            );
+                            Ok(IoCompletion::new(vec![choice], usage)
+                                .with_model(suc.model)
+                                .into())
+                        }
+                        OllamaResponse::Err { error } => Err(error.into_error(&json)),
+                    }
+                }
+                true => {
+                    // Ollama streams newline delimited JSON rather than server sent events
+                    let response_stream: CompletionStream = Box::new(
+                        response
+                            .json_nl_stream::<Value>(8192)
+                            .map_err(|err| err.into()),
+                    );
+                    let handler: ProviderStreamHandler =
+                        StreamedCompletionHandler::<OllamaStreamResponse>::from(response_stream)
+                            .into();
+                    Ok(handler.into())
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum OllamaResponse {
+    Success(OllamaSuccess),
+    Err { error: OllamaError },
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct OllamaSuccess {
+    pub model: String,
+    pub message: OllamaMessage,
+    pub done: bool,
+    /// Only sent by newer versions of Ollama
+    pub done_reason: Option<String>,
+    pub prompt_eval_count: Option<u32>,
+    pub eval_count: Option<u32>,
+}
+
+impl OllamaSuccess {
+    /// Ollama leaves out the counts when it answered from its prompt cache
+    pub fn usage(&self) -> Option<TokenUsage> {
+        ollama_usage(self.prompt_eval_count, self.eval_count)
+    }
+}
+
+pub(super) fn ollama_usage(
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+) -> Option<TokenUsage> {
+    match (prompt_eval_count, eval_count) {
+        (None, None) => None,
+        (prompt, completion) => Some(TokenUsage::new(
+            prompt.unwrap_or_default(),
+            completion.unwrap_or_default(),
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct OllamaMessage {
+    pub role: String,
+    pub content: String,
+}
+
+/// Ollama errors are a bare string under the `error` key
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct OllamaError(pub String);
+impl ProviderResponseError for OllamaError {
+    fn message(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ollama_response_parsed_correctly() {
+        let value = json!({
+            "model": "llama3",
+            "created_at": "2023-12-12T14:13:43.416799Z",
+            "message": {
+                "role": "assistant",
+                "content": "Hello! How are you today?"
+            },
+            "done": true,
+            "total_duration": 5191566416u64,
+            "load_duration": 2154458,
+            "prompt_eval_count": 26,
+            "prompt_eval_duration": 383809000,
+            "eval_count": 298,
+            "eval_duration": 4799921000u64
+        });
+        let res: OllamaResponse = serde_json::from_value(value).unwrap();
+        let expected = OllamaResponse::Success(OllamaSuccess {
+            model: "llama3".to_owned(),
+            message: OllamaMessage {
+                role: "assistant".to_owned(),
+                content: "Hello! How are you today?".to_owned(),
+            },
+            done: true,
+            done_reason: None,
+            prompt_eval_count: Some(26),
+            eval_count: Some(298),
+        });
+        assert_eq!(res, expected);
+
+        let value = json!({"error": "model 'llama9' not found, try pulling it first"});
+        let res: OllamaResponse = serde_json::from_value(value).unwrap();
+        assert_eq!(
+            res,
+            OllamaResponse::Err {
+                error: OllamaError("model 'llama9' not found, try pulling it first".to_owned())
+            }
+        );
+    }
+}
@@ -0,0 +1,36 @@
+use super::requests::{ollama_usage, OllamaMessage};
+use crate::language_models::completions::streaming::{StreamEvent, StreamResponse};
+use serde::Deserialize;
+
+impl StreamResponse for OllamaStreamResponse {
+    fn into_events(self) -> Vec<StreamEvent> {
+        let mut events = vec![];
+        if let Some(message) = self.message {
+            events.push(StreamEvent::TextDelta(message.content));
+        }
+        if let Some(reason) = self.done_reason {
+            events.push(StreamEvent::Stop(reason));
+        }
+        if let Some(usage) = ollama_usage(self.prompt_eval_count, self.eval_count) {
+            events.push(StreamEvent::Usage(usage));
+        }
+        events
+    }
+
+    fn is_final(&self) -> bool {
+        self.done
+    }
+}
+
+/// A single line of Ollama's newline delimited chat stream
+#[derive(Debug, Deserialize, Clone)]
+pub struct OllamaStreamResponse {
+    pub message: Option<OllamaMessage>,
+    pub done: bool,
+    /// Why generation stopped, only sent on the final line
+    #[serde(default)]
+    pub done_reason: Option<String>,
+    /// Counts are only sent on the final, `done` line
+    pub prompt_eval_count: Option<u32>,
+    pub eval_count: Option<u32>,
+}
@@ -1,15 +1,21 @@
-use std::collections::HashMap;
-
 use super::{
     super::inference::{CompletionRequest, CompletionRequestBuilder},
-    requests::OpenAiIoRequest,
+    requests::{OpenAiIoRequest, OpenAiResponse, OpenAiUsage},
+    streaming::OpenAiStreamResponse,
 };
-use crate::language_models::completions::{
-    error::{CompletionError, CompletionResult},
-    functions::{FunctionParam, ParamType},
-    ModelParameters,
+use crate::{
+    agents::memory::MessageStack,
+    language_models::{
+        completions::{
+            error::{CompletionResult, ProviderResponseError},
+            functions::{serialize_params, Function, ToolCall, ToolChoice},
+            streaming::FunctionStreamRequest,
+            usage::TokenUsage,
+            ModelParameters,
+        },
+        secret::sensitive_header,
+    },
 };
-use anyhow::anyhow;
 use reqwest::header::HeaderMap;
 use serde::{de::Error, Deserialize, Serialize};
 use serde_json::{json, Map, Value};
@@ -25,43 +31,8 @@ pub enum OpenAiCompletionModel {
 const GPT3_MODEL_STR: &str = "gpt-3.5-turbo-0125";
 const GPT4_MODEL_STR: &str = "gpt-4-0125-preview";
 
-impl OpenAiCompletionModel {
-    fn serialize_function_params(params: HashMap<String, FunctionParam>) -> Value {
-        let mut all_params = Map::new();
-        let mut req = vec![];
-        for (name, param) in params.iter() {
-            let mut current_param = Map::new();
-            match &param.typ {
-                ParamType::String => {
-                    current_param.insert("type".to_owned(), "string".to_owned().into());
-                }
-                ParamType::Bool => {
-                    current_param.insert("type".to_owned(), "boolean".to_owned().into());
-                }
-                ParamType::Integer => {
-                    current_param.insert("type".to_owned(), "integer".to_owned().into());
-                }
-                ParamType::Enum(variants) => {
-                    current_param.insert("type".to_owned(), "string".to_owned().into());
-                    current_param.insert("enum".to_owned(), json!(variants));
-                }
-            }
-
-            if let Some(desc) = &param.description {
-                current_param.insert("description".to_owned(), desc.to_owned().into());
-            }
-            all_params.insert(name.to_owned(), json!(current_param));
-            if param.required {
-                req.push(name);
-            }
-        }
-        json!({
-            "type": "object",
-            "properties": all_params,
-            "required": json!(req),
-        })
-    }
-}
+/// `ModelParameters` OpenAi has no equivalent for
+const UNSUPPORTED_PARAMS: &[&str] = &["top_k"];
 
 impl CompletionRequestBuilder for OpenAiCompletionModel {
     fn model_str(&self) -> &str {
@@ -77,22 +48,15 @@ impl CompletionRequestBuilder for OpenAiCompletionModel {
 
     fn headers(&self, api_key: &str) -> HeaderMap {
         let mut map = HeaderMap::new();
-        map.insert(
-            "Authorization",
-            format!("Bearer {}", api_key).parse().unwrap(),
-        );
+        if let Ok(value) = sensitive_header(&format!("Bearer {}", api_key)) {
+            map.insert("Authorization", value);
+        }
         map.insert("Content-Type", "application/json".parse().unwrap());
         map
     }
 
     fn serialize_messages(&self, stack: &crate::agents::memory::MessageStack) -> Value {
-        stack
-            .as_ref()
-            .to_owned()
-            .into_iter()
-            .map(|m| m.into())
-            .collect::<Vec<Value>>()
-            .into()
+        serialize_messages(stack)
     }
 
     fn into_io_req(
@@ -100,143 +64,296 @@ impl CompletionRequestBuilder for OpenAiCompletionModel {
         stack: &crate::agents::memory::MessageStack,
         params: &ModelParameters,
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
-        Ok(Box::new(OpenAiIoRequest::new(stack, params, *self, false)))
+        params.ensure_supported("OpenAi", UNSUPPORTED_PARAMS)?;
+        Ok(Box::new(OpenAiIoRequest::new(stack, params, self, false)))
     }
     fn into_stream_req(
         &self,
         stack: &crate::agents::memory::MessageStack,
         params: &ModelParameters,
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
-        Ok(Box::new(OpenAiIoRequest::new(stack, params, *self, true)))
+        params.ensure_supported("OpenAi", UNSUPPORTED_PARAMS)?;
+        Ok(Box::new(OpenAiIoRequest::new(stack, params, self, true)))
     }
-    fn serialize_function(
+    fn serialize_tools(
         &self,
-        stack: &crate::prelude::MessageStack,
-        function: crate::language_models::completions::functions::Function,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
     ) -> CompletionResult<Value> {
-        let mut func_map = Map::new();
-        let params = Self::serialize_function_params(function.params);
-        func_map.insert("name".to_owned(), function.name.clone().into());
-        func_map.insert("description".to_owned(), function.description.into());
-        func_map.insert("parameters".to_owned(), json!(params));
-        let func: Value = func_map.into();
-        info!("function serialized: {:?}", func);
-
-        Ok(json!({
-            "model": self.model_str(),
-            "messages": self.serialize_messages(stack),
-            "functions": [func],
-            "function_call": {"name": function.name}
-        }))
-    }
-
-    fn process_function_response(&self, response_json: Value) -> CompletionResult<Value> {
-        let fn_call = response_json
-            .get("choices")
-            .ok_or(serde_json::Error::missing_field("choices"))?[0]
-            .get("message")
-            .ok_or(serde_json::Error::missing_field("message"))?
-            .get("function_call")
-            .ok_or(serde_json::Error::missing_field("function_call"))?;
-
-        let args_json = serde_json::from_str::<Value>(
-            fn_call
-                .get("arguments")
-                .ok_or(serde_json::Error::missing_field("arguments"))?
-                .as_str()
-                .expect("why did args fail to be coerced to str?"),
-        )?;
-
-        tracing::info!("Args json: {:?}", args_json);
-        let mut args_output: Value = json!({});
-        if let Some(arguments) = args_json.as_object() {
-            for (key, value) in arguments.iter() {
-                args_output
-                    .as_object_mut()
-                    .ok_or(CompletionError::from(anyhow!(
-                        "failed to get args output as mutable array"
-                    )))?
-                    .insert(key.to_string(), value.clone());
-            }
-        }
-        tracing::info!("Args output: {:?}", args_output);
-        Ok(args_output)
+        params.ensure_supported("OpenAi", UNSUPPORTED_PARAMS)?;
+        serialize_tools_request(self, stack, params, functions, choice)
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<Vec<ToolCall>> {
+        process_tools_response_json(response_json)
+    }
+
+    fn into_function_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        function: Function,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        function_stream_request(self, stack, params, function)
+    }
+
+    fn supports_n(&self) -> bool {
+        true
+    }
+
+    fn process_usage(&self, response_json: &Value) -> Option<TokenUsage> {
+        process_usage_json(response_json)
     }
 }
 
-mod tests {
-    use std::collections::HashMap;
+/// OpenAi message format, shared with any OpenAi compatible provider
+pub(super) fn serialize_messages(stack: &MessageStack) -> Value {
+    stack
+        .as_ref()
+        .to_owned()
+        .into_iter()
+        .map(|m| m.into())
+        .collect::<Vec<Value>>()
+        .into()
+}
 
-    use once_cell::sync::Lazy;
-    use serde_json::json;
+/// Builds an OpenAi tools request body, shared with any OpenAi compatible provider
+pub(super) fn serialize_tools_request(
+    builder: &dyn CompletionRequestBuilder,
+    stack: &MessageStack,
+    params: &ModelParameters,
+    functions: &[Function],
+    choice: &ToolChoice,
+) -> CompletionResult<Value> {
+    let tools = functions
+        .iter()
+        .map(|function| {
+            let mut func_map = Map::new();
+            func_map.insert("name".to_owned(), function.name.clone().into());
+            func_map.insert(
+                "description".to_owned(),
+                function.description.clone().into(),
+            );
+            func_map.insert("parameters".to_owned(), serialize_params(&function.params));
+            json!({"type": "function", "function": func_map})
+        })
+        .collect::<Vec<Value>>();
+    info!("tools serialized: {:?}", tools);
 
-    use crate::{
-        language_models::completions::{
-            functions::{FunctionParam, ParamType},
-            openai::builder::OpenAiCompletionModel,
-        },
-        telemetry::{get_subscriber, init_subscriber},
+    let tool_choice = match choice {
+        ToolChoice::Auto => json!("auto"),
+        ToolChoice::Required => json!("required"),
+        ToolChoice::Named(name) => json!({"type": "function", "function": {"name": name}}),
     };
 
+    // Tools requests take the same body as a regular completion, plus the tools
+    let mut req = serde_json::to_value(OpenAiIoRequest::new(stack, params, builder, false))?;
+    req["tools"] = tools.into();
+    req["tool_choice"] = tool_choice;
+    Ok(req)
+}
+
+/// Builds a streamed OpenAi function request, shared with any OpenAi compatible provider
+pub(super) fn function_stream_request(
+    builder: &dyn CompletionRequestBuilder,
+    stack: &MessageStack,
+    params: &ModelParameters,
+    function: Function,
+) -> CompletionResult<Box<dyn CompletionRequest>> {
+    let mut req = builder.serialize_function(stack, params, function)?;
+    req["stream"] = true.into();
+    req["stream_options"] = json!({"include_usage": true});
+    Ok(Box::new(
+        FunctionStreamRequest::<OpenAiStreamResponse>::new(req),
+    ))
+}
+
+/// Pulls every tool call out of an OpenAi tools response
+pub(super) fn process_tools_response_json(response_json: Value) -> CompletionResult<Vec<ToolCall>> {
+    let response: OpenAiResponse = serde_json::from_value(response_json.clone())?;
+    let mut success = match response {
+        OpenAiResponse::Success(suc) => suc,
+        OpenAiResponse::Err { error } => return Err(error.into_error(&response_json)),
+    };
+    if success.choices.is_empty() {
+        return Err(serde_json::Error::missing_field("choices").into());
+    }
+    let tool_calls = success
+        .choices
+        .remove(0)
+        .message
+        .tool_calls
+        .unwrap_or_default();
+
+    let mut calls = vec![];
+    for call in tool_calls.into_iter() {
+        let arguments = serde_json::from_str::<Value>(&call.function.arguments)?;
+        tracing::info!("Args json for {}: {:?}", call.function.name, arguments);
+        calls.push(ToolCall {
+            id: call.id,
+            name: call.function.name,
+            arguments,
+        });
+    }
+    Ok(calls)
+}
+
+/// Reads the `usage` object of an OpenAi response, shared with any OpenAi compatible provider
+pub(super) fn process_usage_json(response_json: &Value) -> Option<TokenUsage> {
+    let usage = response_json.get("usage")?.clone();
+    serde_json::from_value::<OpenAiUsage>(usage)
+        .ok()
+        .map(TokenUsage::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{agents::memory::Message, language_models::completions::error::CompletionError};
+    use std::collections::BTreeMap;
+
     #[test]
-    fn correctly_serialize_params() {
-        let mut params = HashMap::new();
-        params.insert(
-            String::from("location"),
-            FunctionParam {
-                required: false,
-                typ: ParamType::String,
-                description: Some("the city and state, e.g. san francisco, ca".to_owned()),
-            },
-        );
-        params.insert(
-            String::from("format"),
-            FunctionParam {
-                required: true,
-                typ: ParamType::Enum(vec![String::from("celcius"), String::from("fahrenheight")]),
-                description: None,
-            },
+    fn openai_sampling_params_serialized() {
+        let stack = MessageStack::new("SYSTEM");
+        let params = ModelParameters {
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            frequency_penalty: Some(0.5),
+            presence_penalty: Some(-0.5),
+            stop: Some(vec!["\n\n".to_owned()]),
+            seed: Some(42),
+            logit_bias: Some(BTreeMap::from([(50256, -100)])),
+            ..Default::default()
+        };
+        let model = OpenAiCompletionModel::default();
+        let req = model
+            .into_io_req(&stack, &params)
+            .unwrap()
+            .as_json()
+            .unwrap();
+        assert_eq!(req["temperature"], json!(0.7f32));
+        assert_eq!(req["top_p"], json!(0.9f32));
+        assert_eq!(req["frequency_penalty"], json!(0.5f32));
+        assert_eq!(req["presence_penalty"], json!(-0.5f32));
+        assert_eq!(req["stop"], json!(["\n\n"]));
+        assert_eq!(req["seed"], 42);
+        assert_eq!(req["logit_bias"], json!({"50256": -100}));
+        assert!(req.get("top_k").is_none());
+
+        let params = ModelParameters {
+            top_k: Some(40),
+            ..Default::default()
+        };
+        assert!(matches!(
+            model.into_io_req(&stack, &params),
+            Err(CompletionError::UnsupportedParameter {
+                provider: "OpenAi",
+                parameter: "top_k"
+            })
+        ));
+    }
+
+    #[test]
+    fn openai_tools_serialized() {
+        let mut stack = MessageStack::new("SYSTEM");
+        stack.push(Message::new_user("What's the weather in Detroit?"));
+        let functions = vec![
+            Function::try_from("get_weather(location!: string)").unwrap(),
+            Function::try_from("get_time(timezone!: string)").unwrap(),
+        ];
+        let model = OpenAiCompletionModel::default();
+        let params = ModelParameters::default();
+
+        let req = model
+            .serialize_tools(&stack, &params, &functions, &ToolChoice::Auto)
+            .unwrap();
+        assert_eq!(req["tool_choice"], "auto");
+        assert!(req.get("functions").is_none());
+        assert_eq!(req["tools"].as_array().unwrap().len(), 2);
+        assert_eq!(req["tools"][0]["type"], "function");
+        assert_eq!(req["tools"][0]["function"]["name"], "get_weather");
+        assert_eq!(
+            req["tools"][0]["function"]["parameters"]["required"],
+            json!(["location"])
         );
-        params.insert(
-            String::from("num_days"),
-            FunctionParam {
-                required: true,
-                typ: ParamType::Integer,
-                description: Some("the number of days to forcast".to_owned()),
-            },
+
+        let req = model
+            .serialize_tools(&stack, &params, &functions, &ToolChoice::Required)
+            .unwrap();
+        assert_eq!(req["tool_choice"], "required");
+
+        let req = model
+            .serialize_function(&stack, &params, functions.into_iter().nth(1).unwrap())
+            .unwrap();
+        assert_eq!(
+            req["tool_choice"],
+            json!({"type": "function", "function": {"name": "get_time"}})
         );
+        assert_eq!(req["tools"].as_array().unwrap().len(), 1);
+    }
 
-        let expected = json!({
-                "type": "object",
-                "properties": {
-                  "location": {
-                    "type": "string",
-                    "description": "the city and state, e.g. san francisco, ca"
-                  },
-                    "num_days": {
-                    "type": "integer",
-                    "description": "the number of days to forcast",
-                    },
-                  "format": {
-                    "type": "string",
-                    "enum": ["celcius", "fahrenheight"]
-                  }
+    #[test]
+    fn openai_parallel_tool_calls_processed() {
+        let response = json!({
+            "id": "chatcmpl-abc123",
+            "object": "chat.completion",
+            "created": 1699896916,
+            "model": "gpt-4-0125-preview",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {
+                            "id": "call_abc123",
+                            "type": "function",
+                            "function": {
+                                "name": "get_weather",
+                                "arguments": "{\"location\": \"Detroit, MI\"}"
+                            }
+                        },
+                        {
+                            "id": "call_def456",
+                            "type": "function",
+                            "function": {
+                                "name": "get_time",
+                                "arguments": "{\"timezone\": \"EST\"}"
+                            }
+                        }
+                    ]
+                },
+                "logprobs": null,
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 82, "completion_tokens": 17, "total_tokens": 99}
+        });
+        let model = OpenAiCompletionModel::default();
+        let calls = model.process_tools_response(response.clone()).unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                ToolCall {
+                    id: "call_abc123".to_owned(),
+                    name: "get_weather".to_owned(),
+                    arguments: json!({"location": "Detroit, MI"}),
                 },
-                "required": ["num_days", "format"]}
+                ToolCall {
+                    id: "call_def456".to_owned(),
+                    name: "get_time".to_owned(),
+                    arguments: json!({"timezone": "EST"}),
+                },
+            ]
+        );
+        assert_eq!(
+            model.process_usage(&response),
+            Some(TokenUsage::new(82, 17))
+        );
+        assert_eq!(
+            model.process_function_response(response).unwrap(),
+            json!({"location": "Detroit, MI"})
         );
-
-        let serialized = OpenAiCompletionModel::serialize_function_params(params);
-
-        for (k, v) in expected["properties"].as_object().unwrap().into_iter() {
-            assert_eq!(v, &serialized["properties"][k])
-        }
-        for r in expected["required"].as_array().unwrap() {
-            assert!(serialized["required"]
-                .as_array()
-                .unwrap()
-                .iter()
-                .find(|v| *v == r)
-                .is_some())
-        }
     }
 }
@@ -0,0 +1,204 @@
+use super::{
+    super::inference::{CompletionRequest, CompletionRequestBuilder},
+    builder::{
+        function_stream_request, process_tools_response_json, process_usage_json,
+        serialize_messages, serialize_tools_request,
+    },
+    requests::OpenAiIoRequest,
+};
+use crate::{
+    agents::memory::MessageStack,
+    language_models::{
+        completions::{
+            error::{CompletionError, CompletionResult},
+            functions::{Function, ToolCall, ToolChoice},
+            usage::TokenUsage,
+            ModelParameters,
+        },
+        secret::sensitive_header,
+    },
+};
+use reqwest::header::{HeaderMap, HeaderName};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// How the api key of a `CompletionModel` is sent to an OpenAi compatible server
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum CompatibleAuth {
+    /// No auth header is sent, most local servers don't need one
+    None,
+    /// `Authorization: Bearer <api_key>`, same as OpenAi
+    #[default]
+    Bearer,
+    /// The api key is sent as is under the given header name
+    Header(String),
+}
+
+impl CompatibleAuth {
+    /// Errors if a `Header` name is not a valid HTTP header name
+    pub fn validate(&self) -> CompletionResult<()> {
+        match self {
+            Self::Header(name) if HeaderName::from_bytes(name.as_bytes()).is_err() => {
+                Err(CompletionError::InvalidHeader(name.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn deserialize_auth<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<CompatibleAuth, D::Error> {
+    let auth = CompatibleAuth::deserialize(deserializer)?;
+    auth.validate().map_err(serde::de::Error::custom)?;
+    Ok(auth)
+}
+
+/// Any server speaking OpenAi's chat completions protocol, such as vLLM, LM Studio or llama.cpp.
+/// Requests, streaming & function calling are all handled exactly like `OpenAiCompletionModel`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OpenAiCompatibleModel {
+    url: String,
+    model: String,
+    #[serde(deserialize_with = "deserialize_auth")]
+    auth: CompatibleAuth,
+}
+
+impl OpenAiCompatibleModel {
+    /// `base_url` should be the root of the OpenAi api, for example `http://localhost:8000/v1`.
+    /// `/chat/completions` is appended to it
+    pub fn new(base_url: &str, model: &str) -> Self {
+        Self {
+            url: format!("{}/chat/completions", base_url.trim_end_matches('/')),
+            model: model.to_owned(),
+            auth: CompatibleAuth::default(),
+        }
+    }
+
+    /// Errors if a `CompatibleAuth::Header` name is not a valid HTTP header name
+    pub fn with_auth(mut self, auth: CompatibleAuth) -> CompletionResult<Self> {
+        auth.validate()?;
+        self.auth = auth;
+        Ok(self)
+    }
+}
+
+impl CompletionRequestBuilder for OpenAiCompatibleModel {
+    fn model_str(&self) -> &str {
+        &self.model
+    }
+
+    fn url_str(&self) -> &str {
+        &self.url
+    }
+
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        match &self.auth {
+            CompatibleAuth::None => {}
+            CompatibleAuth::Bearer => {
+                if let Ok(value) = sensitive_header(&format!("Bearer {}", api_key)) {
+                    map.insert("Authorization", value);
+                }
+            }
+            // Names are checked by `with_auth` & when deserializing
+            CompatibleAuth::Header(name) => {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_bytes(name.as_bytes()),
+                    sensitive_header(api_key),
+                ) {
+                    map.insert(name, value);
+                }
+            }
+        }
+        map.insert("Content-Type", "application/json".parse().unwrap());
+        map
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        serialize_messages(stack)
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OpenAiIoRequest::new(stack, params, self, false)))
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OpenAiIoRequest::new(stack, params, self, true)))
+    }
+
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        serialize_tools_request(self, stack, params, functions, choice)
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<Vec<ToolCall>> {
+        process_tools_response_json(response_json)
+    }
+
+    fn into_function_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        function: Function,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        function_stream_request(self, stack, params, function)
+    }
+
+    /// Servers that ignore `n` return a single choice
+    fn supports_n(&self) -> bool {
+        true
+    }
+
+    fn process_usage(&self, response_json: &Value) -> Option<TokenUsage> {
+        process_usage_json(response_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compatible_url_and_headers() {
+        let model = OpenAiCompatibleModel::new("http://localhost:8000/v1/", "mistral-7b");
+        assert_eq!(model.url_str(), "http://localhost:8000/v1/chat/completions");
+        assert_eq!(model.model_str(), "mistral-7b");
+        assert_eq!(model.headers("key")["Authorization"], "Bearer key");
+
+        let model = model.with_auth(CompatibleAuth::None).unwrap();
+        assert!(model.headers("key").get("Authorization").is_none());
+
+        let model = model
+            .with_auth(CompatibleAuth::Header("api-key".to_owned()))
+            .unwrap();
+        assert_eq!(model.headers("key")["api-key"], "key");
+    }
+
+    #[test]
+    fn invalid_auth_header_names_are_rejected() {
+        let model = OpenAiCompatibleModel::new("http://localhost:8000/v1", "mistral-7b");
+        let invalid = CompatibleAuth::Header("api key\n".to_owned());
+        assert!(matches!(
+            model.clone().with_auth(invalid.clone()),
+            Err(CompletionError::InvalidHeader(_))
+        ));
+
+        let mut json = serde_json::to_value(&model).unwrap();
+        json["auth"] = serde_json::to_value(invalid).unwrap();
+        assert!(serde_json::from_value::<OpenAiCompatibleModel>(json).is_err());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod builder;
+pub mod compatible;
 pub mod requests;
 pub mod streaming;
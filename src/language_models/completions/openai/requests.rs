@@ -1,53 +1,80 @@
-use super::{
-    super::inference::CompletionRequest, builder::OpenAiCompletionModel,
-    streaming::OpenAiStreamResponse,
-};
+use super::{super::inference::CompletionRequest, streaming::OpenAiStreamResponse};
 use crate::{
     agents::memory::MessageStack,
     language_models::completions::{
-        error::{CompletionError, CompletionResult, ProviderResponseError},
-        inference::{CompletionRequestBuilder, CompletionResponse, ProcessResponseReturn},
-        streaming::{CompletionStream, ProviderStreamHandler, StreamedCompletionHandler},
+        error::{CompletionResult, ProviderResponseError},
+        inference::{
+            CompletionChoice, CompletionRequestBuilder, IoCompletion, ProcessResponseReturn,
+        },
+        streaming::{sse::sse_json_stream, ProviderStreamHandler, StreamedCompletionHandler},
+        usage::TokenUsage,
         ModelParameters,
     },
 };
-use anyhow::anyhow;
-use futures::TryStreamExt;
 use reqwest::Response;
-use reqwest_streams::JsonStreamResponse;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::time::Duration;
+use std::collections::BTreeMap;
 use tracing::info;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct OpenAiIoRequest {
     pub model: String,
     pub messages: Value,
-    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Not part of OpenAi's api, only sent to compatible servers that accept it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
     pub max_tokens: u32,
     pub stream: bool,
     pub n: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<BTreeMap<u32, i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+/// Asks for a final chunk carrying the usage of the whole stream
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct StreamOptions {
+    pub include_usage: bool,
 }
 
 impl OpenAiIoRequest {
     pub fn new(
         stack: &MessageStack,
         params: &ModelParameters,
-        typ: OpenAiCompletionModel,
+        typ: &dyn CompletionRequestBuilder,
         stream: bool,
     ) -> Self {
-        let temperature = match params.temperature().ok() {
-            Some(t) => t,
-            None => 0.7,
-        };
         OpenAiIoRequest {
             model: typ.model_str().to_string(),
-            messages: CompletionRequestBuilder::serialize_messages(&typ, stack),
-            temperature,
+            messages: typ.serialize_messages(stack),
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: params.top_k,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
             stream,
             max_tokens: params.max_tokens.unwrap_or(1000),
             n: params.n.unwrap_or(1),
+            stop: params.stop.clone(),
+            seed: params.seed,
+            logit_bias: params.logit_bias.clone(),
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
         }
     }
 }
@@ -61,29 +88,34 @@ impl CompletionRequest for OpenAiIoRequest {
         Box::pin(async move {
             match self.stream {
                 false => {
-                    let json = response.json().await?;
+                    let json: Value = response.json().await?;
                     tracing::warn!("got response:  {json:#?}");
-                    let response: OpenAiResponse = serde_json::from_value(json)?;
+                    let response: OpenAiResponse = serde_json::from_value(json.clone())?;
                     return match response {
-                        OpenAiResponse::Success(mut suc) => {
-                            let content = suc.choices.remove(0).message.content.ok_or(
-                                CompletionError::from(anyhow!("No content in success message")),
-                            )?;
-                            Ok(CompletionResponse::from(content))
+                        OpenAiResponse::Success(suc) => {
+                            // Filtered choices have no content, their finish reason says why
+                            let choices = suc
+                                .choices
+                                .into_iter()
+                                .map(|choice| {
+                                    CompletionChoice::new(
+                                        choice.message.content.unwrap_or_default(),
+                                        choice.finish_reason.as_deref(),
+                                    )
+                                })
+                                .collect();
+                            let completion = IoCompletion::new(choices, suc.usage.map(Into::into));
+                            Ok(match suc.model {
+                                Some(model) => completion.with_model(model),
+                                None => completion,
+                            }
+                            .into())
                         }
-                        OpenAiResponse::Err { error } => Err(error.into_error()),
+                        OpenAiResponse::Err { error } => Err(error.into_error(&json)),
                     };
                 }
                 true => {
-                    let response_stream: CompletionStream = Box::new(
-                        tokio::time::timeout(Duration::from_secs(10), async {
-                            response
-                                .json_array_stream::<Value>(1024)
-                                .map_err(|err| err.into())
-                        })
-                        .await
-                        .map_err(|_| CompletionError::StreamTimeout)?,
-                    );
+                    let response_stream = sse_json_stream(response.bytes_stream());
                     let handler: ProviderStreamHandler =
                         StreamedCompletionHandler::<OpenAiStreamResponse>::from(response_stream)
                             .into();
@@ -103,27 +135,57 @@ pub enum OpenAiResponse {
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct OpenAiSuccess {
-    pub usage: OpenAiUsage,
+    /// Some compatible servers leave this out
+    pub model: Option<String>,
+    /// Many compatible servers leave this out too
+    pub usage: Option<OpenAiUsage>,
     pub choices: Vec<Choice>,
 }
 
-impl ProviderResponseError for OpenAiErr {}
+impl ProviderResponseError for OpenAiErr {
+    fn message(&self) -> &str {
+        &self.message
+    }
+    fn error_type(&self) -> Option<&str> {
+        self.error_type.as_deref()
+    }
+    fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+}
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct OpenAiErr {
-    pub code: String,
+    /// OpenAi sends `null` for many errors
+    pub code: Option<String>,
+    #[serde(rename = "type", default)]
+    pub error_type: Option<String>,
     pub message: String,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct Choice {
     pub message: GptMessage,
+    pub finish_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct GptMessage {
     pub role: String,
     pub content: Option<String>,
-    pub function_call: Option<Value>,
+    pub tool_calls: Option<Vec<OpenAiToolCall>>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct OpenAiToolCall {
+    pub id: String,
+    pub function: OpenAiFunctionCall,
+}
+
+/// `arguments` is a string of JSON generated by the model
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct OpenAiFunctionCall {
+    pub name: String,
+    pub arguments: String,
 }
 
 #[derive(Debug, serde::Deserialize, Clone, PartialEq, Eq)]
@@ -133,6 +195,15 @@ pub struct OpenAiUsage {
     pub total_tokens: i32,
 }
 
+impl From<OpenAiUsage> for TokenUsage {
+    fn from(value: OpenAiUsage) -> Self {
+        TokenUsage::new(
+            value.prompt_tokens as u32,
+            value.completion_tokens.unwrap_or_default() as u32,
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -164,21 +235,38 @@ mod tests {
 
         let res: OpenAiResponse = serde_json::from_value(value).unwrap();
         let expected = OpenAiResponse::Success(OpenAiSuccess {
-            usage: OpenAiUsage {
+            model: Some("gpt-3.5-turbo-0613".to_string()),
+            usage: Some(OpenAiUsage {
                 prompt_tokens: 13,
                 completion_tokens: Some(7),
                 total_tokens: 20,
-            },
+            }),
             choices: vec![{
                 Choice {
                     message: GptMessage {
                         role: "assistant".to_string(),
                         content: Some("\n\nThis is a test!".to_string()),
-                        function_call: None,
+                        tool_calls: None,
                     },
+                    finish_reason: Some("stop".to_string()),
                 }
             }],
         });
         assert_eq!(res, expected);
     }
+
+    #[test]
+    fn compatible_response_without_usage_is_parsed() {
+        let value = json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "hi"},
+                "finish_reason": "stop"
+            }]
+        });
+        let OpenAiResponse::Success(success) = serde_json::from_value(value).unwrap() else {
+            panic!("expected a successful response");
+        };
+        assert_eq!(success.usage, None);
+        assert_eq!(success.model, None);
+    }
 }
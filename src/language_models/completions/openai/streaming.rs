@@ -1,34 +1,112 @@
-use super::super::streaming::{CompletionStreamStatus, StreamResponse};
+use super::{
+    super::streaming::{StreamEvent, StreamResponse},
+    requests::OpenAiUsage,
+};
 use serde::Deserialize;
 
-impl StreamResponse for OpenAiStreamResponse {}
+impl StreamResponse for OpenAiStreamResponse {
+    /// The stream ends with `[DONE]` rather than a final chunk, as a usage chunk with no choices
+    /// may still follow the one carrying `finish_reason`
+    fn into_events(self) -> Vec<StreamEvent> {
+        let mut events = vec![];
+        for choice in self.choices {
+            if let Some(content) = choice.delta.content {
+                events.push(StreamEvent::TextDelta(content));
+            }
+            for call in choice.delta.tool_calls.into_iter().flatten() {
+                let function = call.function.unwrap_or_default();
+                events.push(StreamEvent::ToolCallDelta {
+                    index: call.index,
+                    id: call.id,
+                    name: function.name,
+                    arguments: function.arguments.unwrap_or_default(),
+                });
+            }
+            if let Some(reason) = choice.finish_reason {
+                events.push(StreamEvent::Stop(reason));
+            }
+        }
+        if let Some(usage) = self.usage {
+            events.push(StreamEvent::Usage(usage.into()));
+        }
+        events
+    }
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct OpenAiStreamResponse {
+    #[serde(default)]
     pub choices: Vec<StreamChoice>,
+    /// Only present on the final chunk, when `stream_options.include_usage` is set
+    pub usage: Option<OpenAiUsage>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct StreamChoice {
     pub delta: StreamDelta,
+    pub finish_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct StreamDelta {
     pub role: Option<String>,
     pub content: Option<String>,
+    pub tool_calls: Option<Vec<StreamToolCall>>,
 }
 
-impl Into<CompletionStreamStatus> for OpenAiStreamResponse {
-    fn into(self) -> CompletionStreamStatus {
-        match self.choices[0].delta.content.to_owned() {
-            Some(response) => CompletionStreamStatus::Working(
-                response
-                    .trim_start_matches('"')
-                    .trim_end_matches('"')
-                    .to_string(),
-            ),
-            None => CompletionStreamStatus::Finished,
-        }
+#[derive(Debug, Deserialize, Clone)]
+struct StreamToolCall {
+    index: usize,
+    /// Only sent with the first delta of each call, as is the function name
+    id: Option<String>,
+    function: Option<StreamFunction>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct StreamFunction {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::usage::TokenUsage;
+    use serde_json::json;
+
+    #[test]
+    fn openai_chunks_become_events() {
+        let chunk: OpenAiStreamResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-9pL1kX4",
+            "object": "chat.completion.chunk",
+            "choices": [{
+                "index": 0,
+                "delta": {"tool_calls": [{"index": 0, "id": "call_abc", "type": "function", "function": {"name": "get_weather", "arguments": "{\"lo"}}]},
+                "finish_reason": null
+            }]
+        }))
+        .unwrap();
+        assert_eq!(
+            chunk.into_events(),
+            [StreamEvent::ToolCallDelta {
+                index: 0,
+                id: Some("call_abc".to_owned()),
+                name: Some("get_weather".to_owned()),
+                arguments: "{\"lo".to_owned()
+            }]
+        );
+
+        let last: OpenAiStreamResponse = serde_json::from_value(json!({
+            "choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 19, "completion_tokens": 2, "total_tokens": 21}
+        }))
+        .unwrap();
+        assert_eq!(
+            last.into_events(),
+            [
+                StreamEvent::Stop("stop".to_owned()),
+                StreamEvent::Usage(TokenUsage::new(19, 2))
+            ]
+        );
     }
 }
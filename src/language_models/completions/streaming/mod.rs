@@ -1,33 +1,62 @@
 use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
+use tokio::task::AbortHandle;
 use tracing::warn;
 use tracing_log::log::info;
 pub mod error;
+pub mod partial_json;
+pub mod sse;
 use crate::agents::memory::Message;
 use crate::agents::Agent;
-use anyhow::anyhow;
+use crate::language_models::rate_limit::Permit;
 pub use error::*;
 use futures::Stream;
 use futures_util::StreamExt;
 use serde::Deserialize;
 
 use super::{
-    anthropic::streaming::AnthropicStreamResponse, openai::streaming::OpenAiStreamResponse,
+    anthropic::streaming::AnthropicStreamResponse,
+    error::{CompletionResult, ProviderError},
+    functions::ToolCall,
+    inference::{CompletionRequest, ProcessResponseReturn},
+    ollama::streaming::OllamaStreamResponse,
+    openai::streaming::OpenAiStreamResponse,
+    usage::TokenUsage,
 };
 
-pub(in crate::language_models) type CompletionStream =
-    Box<dyn Stream<Item = StreamResult<Value>> + Send + Unpin>;
+/// Raw JSON chunks coming off of a provider's response body
+pub type CompletionStream = Box<dyn Stream<Item = StreamResult<Value>> + Send + Unpin>;
 
 pub(in crate::language_models) type CompletionStreamReceiver =
-    tokio::sync::mpsc::Receiver<Result<CompletionStreamStatus, StreamError>>;
+    tokio::sync::mpsc::Receiver<Result<StreamThreadMessage, StreamError>>;
 pub(in crate::language_models) type CompletionStreamSender =
-    tokio::sync::mpsc::Sender<Result<CompletionStreamStatus, StreamError>>;
+    tokio::sync::mpsc::Sender<Result<StreamThreadMessage, StreamError>>;
 
+/// A chunk of a provider's stream
 pub trait StreamResponse:
-    for<'de> Deserialize<'de> + Debug + Into<CompletionStreamStatus> + Clone + Send + Sync + 'static
+    for<'de> Deserialize<'de> + Debug + Clone + Send + Sync + 'static
 {
+    /// What this chunk carries, in order. Usage counts are treated as running totals, so a later
+    /// chunk's counts replace an earlier one's
+    fn into_events(self) -> Vec<StreamEvent>;
+
+    /// Whether the provider sends nothing after this chunk. Streams also finish when the
+    /// response body ends
+    fn is_final(&self) -> bool {
+        false
+    }
+}
+
+/// Everything the completion stream thread sends back to its handler
+#[derive(Debug)]
+pub(in crate::language_models) enum StreamThreadMessage {
+    Event(StreamEvent),
+    Finished,
 }
 
 #[derive(Debug)]
@@ -39,10 +68,55 @@ pub enum CompletionStreamStatus {
     Finished,
 }
 
+/// What a streamed completion yields, the stream ends once the provider has finished
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Text generated since the last delta, never empty
+    TextDelta(String),
+    /// Part of the JSON arguments of a tool call. Deltas of the same call share an `index`, the
+    /// first one also carries the call's `id` & function `name`
+    ToolCallDelta {
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: String,
+    },
+    /// A whole tool call with its arguments parsed, sent for each call once the provider has
+    /// finished the stream
+    ToolCall(ToolCall),
+    /// Usage of the whole completion so far
+    Usage(TokenUsage),
+    /// Why the model stopped, such as `stop`, `end_turn` or `tool_calls`
+    Stop(String),
+    /// Keep alive sent by the provider
+    Ping,
+    /// An error the provider sent in place of the rest of the stream, nothing follows it
+    Error(ProviderError),
+}
+
+/// Object safe handle over a `StreamedCompletionHandler<T>` of any `StreamResponse` type.
+/// This is what lets custom providers return streamed completions
+pub trait CustomStreamHandler:
+    Stream<Item = StreamResult<StreamEvent>> + Debug + Send + Unpin
+{
+    fn commit(&mut self, agent: &mut Agent) -> bool;
+}
+
+impl<T> CustomStreamHandler for StreamedCompletionHandler<T>
+where
+    T: StreamResponse,
+{
+    fn commit(&mut self, agent: &mut Agent) -> bool {
+        StreamedCompletionHandler::commit(self, agent)
+    }
+}
+
 #[derive(Debug)]
 pub enum ProviderStreamHandler {
     OpenAi(StreamedCompletionHandler<OpenAiStreamResponse>),
     Anthropic(StreamedCompletionHandler<AnthropicStreamResponse>),
+    Ollama(StreamedCompletionHandler<OllamaStreamResponse>),
+    Custom(Box<dyn CustomStreamHandler>),
 }
 
 impl From<StreamedCompletionHandler<OpenAiStreamResponse>> for ProviderStreamHandler {
@@ -57,12 +131,101 @@ impl From<StreamedCompletionHandler<AnthropicStreamResponse>> for ProviderStream
     }
 }
 
+/// A streamed function completion. The body is built by `serialize_function` with streaming
+/// turned on & the response is read as server sent events
+pub(in crate::language_models) struct FunctionStreamRequest<T> {
+    body: Value,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> FunctionStreamRequest<T> {
+    pub fn new(body: Value) -> Self {
+        Self {
+            body,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Debug for FunctionStreamRequest<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FunctionStreamRequest")
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl<T> CompletionRequest for FunctionStreamRequest<T>
+where
+    T: StreamResponse,
+    StreamedCompletionHandler<T>: Into<ProviderStreamHandler>,
+{
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(self.body.clone())
+    }
+
+    fn process_response(&self, response: reqwest::Response) -> ProcessResponseReturn<'_> {
+        Box::pin(async move {
+            let handler =
+                StreamedCompletionHandler::<T>::from(sse::sse_json_stream(response.bytes_stream()));
+            Ok(handler.into().into())
+        })
+    }
+}
+
 pub struct StreamedCompletionHandler<T> {
     phantom: PhantomData<T>,
     stream: Option<CompletionStream>,
     sender: Option<CompletionStreamSender>,
     receiver: CompletionStreamReceiver,
     pub message_content: String,
+    usage: Option<TokenUsage>,
+    /// The task reading the provider's stream, aborted when the handler is dropped
+    task: Option<AbortHandle>,
+    /// The provider finished the stream
+    finished: bool,
+    /// The message has been added to an agent
+    committed: bool,
+    /// Tool calls streamed so far, by index
+    tool_calls: BTreeMap<usize, ToolCallBuffer>,
+    /// Events to yield before the stream ends
+    pending: VecDeque<StreamResult<StreamEvent>>,
+    /// Rate limit permit of the request, settled with the streamed usage once the stream ends
+    permit: Option<Permit>,
+}
+
+/// The parts of a tool call received so far
+#[derive(Debug, Default)]
+struct ToolCallBuffer {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl ToolCallBuffer {
+    /// Calls to functions without parameters may stream no arguments at all
+    fn parse(&self) -> StreamResult<ToolCall> {
+        let arguments = match self.arguments.trim() {
+            "" => Value::Object(Default::default()),
+            arguments => serde_json::from_str(arguments)?,
+        };
+        Ok(ToolCall {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            arguments,
+        })
+    }
+}
+
+/// No field is ever pinned, `T` is only a marker
+impl<T> Unpin for StreamedCompletionHandler<T> {}
+
+impl<T> Drop for StreamedCompletionHandler<T> {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
 }
 
 impl<T> std::fmt::Debug for StreamedCompletionHandler<T> {
@@ -72,6 +235,12 @@ impl<T> std::fmt::Debug for StreamedCompletionHandler<T> {
             .field("sender", &self.sender)
             .field("phantom", &self.phantom)
             .field("receiver", &self.receiver)
+            .field("usage", &self.usage)
+            .field("task", &self.task)
+            .field("finished", &self.finished)
+            .field("committed", &self.committed)
+            .field("tool_calls", &self.tool_calls)
+            .field("permit", &self.permit)
             .finish()
     }
 }
@@ -86,23 +255,104 @@ impl<T> From<CompletionStream> for StreamedCompletionHandler<T> {
             sender: Some(tx),
             receiver: rx,
             message_content: String::new(),
+            usage: None,
+            task: None,
+            finished: false,
+            committed: false,
+            tool_calls: BTreeMap::new(),
+            pending: VecDeque::new(),
+            permit: None,
         }
     }
 }
 
+impl From<StreamedCompletionHandler<OllamaStreamResponse>> for ProviderStreamHandler {
+    fn from(value: StreamedCompletionHandler<OllamaStreamResponse>) -> Self {
+        Self::Ollama(value)
+    }
+}
+
 impl ProviderStreamHandler {
+    /// Wrap a handler over a custom provider's `StreamResponse` type.
+    /// Custom `CompletionRequest`s should return this from `process_response`
+    pub fn custom<T: StreamResponse>(handler: StreamedCompletionHandler<T>) -> Self {
+        Self::Custom(Box::new(handler))
+    }
+
+    /// Waits for the first chunk of the stream, erroring if the provider sent an error in its place.
+    /// Custom handlers are not checked
+    pub(crate) async fn first_chunk(&mut self) -> StreamResult<()> {
+        match self {
+            Self::OpenAi(inner) => inner.first_chunk().await,
+            Self::Anthropic(inner) => inner.first_chunk().await,
+            Self::Ollama(inner) => inner.first_chunk().await,
+            Self::Custom(_) => Ok(()),
+        }
+    }
+
+    /// Settles `permit` once the stream ends. Custom handlers keep the estimate
+    pub(crate) fn set_permit(&mut self, permit: Option<Permit>) {
+        match self {
+            Self::OpenAi(inner) => inner.permit = permit,
+            Self::Anthropic(inner) => inner.permit = permit,
+            Self::Ollama(inner) => inner.permit = permit,
+            Self::Custom(_) => {}
+        }
+    }
+
+    /// Adds the streamed message to `agent`'s cache & records its usage. Meant to be called once
+    /// the stream has ended, returns `false` if the message was already committed
+    pub fn commit(&mut self, agent: &mut Agent) -> bool {
+        match self {
+            Self::OpenAi(inner) => inner.commit(agent),
+            Self::Anthropic(inner) => inner.commit(agent),
+            Self::Ollama(inner) => inner.commit(agent),
+            Self::Custom(inner) => inner.commit(agent),
+        }
+    }
+
+    /// Waits up to a second for the next text delta, other events are skipped. Once the stream ends the message is committed to
+    /// `agent` & `Finished` is returned, after that `None`.
+    /// Consuming the handler as a `Stream` & calling `commit` does not borrow the agent throughout
     #[tracing::instrument("Receive tokens from completion stream", skip(self))]
     pub async fn receive(
         &mut self,
         agent: &mut Agent,
     ) -> StreamResult<Option<CompletionStreamStatus>> {
-        let response = match self {
-            Self::OpenAi(inner) => inner.receive(agent).await,
-            Self::Anthropic(inner) => inner.receive(agent).await,
-        };
+        loop {
+            let event = tokio::time::timeout(Duration::from_millis(1000), self.next())
+                .await
+                .map_err(|_| StreamError::ReceiverTimeout)?;
+            match event {
+                Some(Ok(StreamEvent::TextDelta(text))) => {
+                    return Ok(Some(CompletionStreamStatus::Working(text)))
+                }
+                Some(Ok(StreamEvent::Error(err))) => {
+                    let json = serde_json::from_str(&err.body).unwrap_or(Value::String(err.body));
+                    return Err(StreamError::from(json));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err),
+                None => {
+                    return Ok(self
+                        .commit(agent)
+                        .then_some(CompletionStreamStatus::Finished))
+                }
+            }
+        }
+    }
+}
+
+impl Stream for ProviderStreamHandler {
+    type Item = StreamResult<StreamEvent>;
 
-        tracing::warn!("got stream response:  {response:#?}");
-        response
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Self::OpenAi(inner) => inner.poll_next_unpin(cx),
+            Self::Anthropic(inner) => inner.poll_next_unpin(cx),
+            Self::Ollama(inner) => inner.poll_next_unpin(cx),
+            Self::Custom(inner) => inner.poll_next_unpin(cx),
+        }
     }
 }
 
@@ -110,92 +360,197 @@ impl<T> StreamedCompletionHandler<T>
 where
     T: StreamResponse,
 {
-    /// Returns tokens until finished, when finished, sends an update cache request with the full
-    /// message. Best used in a while loop
-    #[tracing::instrument("Receive tokens from completion stream", skip(self))]
-    async fn receive(&mut self, agent: &mut Agent) -> StreamResult<Option<CompletionStreamStatus>> {
-        if self.sender.is_some() && self.stream.is_some() {
-            tracing::info!("Telling thread to run");
-            self.spawn()?;
-        }
-        if let Some(result) =
-            tokio::time::timeout(Duration::from_millis(1000), self.receiver.recv())
-                .await
-                .map_err(|_| StreamError::ReceiverTimeout)?
-        {
-            match result? {
-                CompletionStreamStatus::Working(token) => {
-                    self.message_content.push_str(&token);
-                    return Ok(Some(CompletionStreamStatus::Working(token.to_string())));
-                }
-                // CompletionStreamStatus::Error(json) => {
-                //     tracing::info!("Stream recieved an error: {json:#?}");
-                //     return Err(StreamError::from(json));
-                // }
-                CompletionStreamStatus::Finished => {
-                    tracing::info!("Stream finished with content: {}", self.message_content);
-                    let message = Message::new_assistant(&self.message_content);
-                    agent.cache.push(message);
-                    return Ok(Some(CompletionStreamStatus::Finished));
-                }
+    /// Pulls the first chunk off of the stream before it is spawned & puts it back in front
+    async fn first_chunk(&mut self) -> StreamResult<()> {
+        let Some(mut stream) = self.stream.take() else {
+            return Ok(());
+        };
+        let first = match stream.next().await {
+            Some(chunk) => chunk?,
+            None => {
+                self.stream = Some(stream);
+                return Ok(());
             }
+        };
+        if serde_json::from_value::<T>(first.clone()).is_err() {
+            return Err(StreamError::from(first));
         }
-        tracing::info!("received none");
-        Ok(None)
+        self.stream = Some(Box::new(futures::stream::iter([Ok(first)]).chain(stream)));
+        Ok(())
+    }
+
+    /// Adds the streamed message to `agent`'s cache & records its usage, returns `false` if it
+    /// was already committed. Like `Agent::function_completion`, a stream of only tool calls
+    /// adds no message
+    pub fn commit(&mut self, agent: &mut Agent) -> bool {
+        if std::mem::replace(&mut self.committed, true) {
+            return false;
+        }
+        if !self.finished {
+            warn!("Committing a stream that has not finished");
+        }
+        tracing::info!("Stream finished with content: {}", self.message_content);
+        if !self.message_content.is_empty() || self.tool_calls.is_empty() {
+            agent
+                .cache
+                .push(Message::new_assistant(&self.message_content));
+        }
+        agent.record_usage(self.usage);
+        true
+    }
+
+    /// Corrects the rate limit estimate with the usage streamed
+    fn settle(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            permit.settle(self.usage);
+        }
+    }
+
+    /// Adds a tool call delta to the call it belongs to
+    fn buffer_tool_call(
+        &mut self,
+        index: usize,
+        id: &Option<String>,
+        name: &Option<String>,
+        arguments: &str,
+    ) {
+        let call = self.tool_calls.entry(index).or_default();
+        if let Some(id) = id {
+            call.id.clone_from(id);
+        }
+        if let Some(name) = name {
+            call.name.clone_from(name);
+        }
+        call.arguments.push_str(arguments);
     }
 
     #[tracing::instrument("Spawn completion stream thread", skip(self))]
     fn spawn(&mut self) -> Result<(), StreamError> {
         let mut stream = self.stream.take().unwrap();
         let tx = self.sender.take().unwrap();
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             loop {
-                tracing::info!("Beginning of completion stream thread loop");
-                match CompletionStreamingThread::poll_stream_for_type::<T>(&mut stream).await {
-                    Ok(type_option) => {
-                        let status: CompletionStreamStatus = match type_option {
-                            Some(ret) => match ret {
-                                StreamPollReturn::Ok(typ) => <T as Clone>::clone(&(typ)).into(),
-                                StreamPollReturn::Err(json) => {
-                                    tx.send(Err(StreamError::from(json)))
-                                        .await
-                                        .expect("could not send");
-                                    break;
-                                }
-                            },
-                            None => CompletionStreamStatus::Finished,
-                        };
-                        tracing::info!("Got status: {:?}", status);
-
-                        let break_loop = match &status {
-                            &CompletionStreamStatus::Finished => true,
-                            _ => false,
-                        };
-
-                        tx.send(Ok(status)).await.map_err(|err| {
-                            StreamError::Undefined(anyhow!("Unexpected Error: {:?}", err))
-                        })?;
-
-                        if break_loop {
+                let (events, finished) =
+                    match CompletionStreamingThread::poll_stream_for_type::<T>(&mut stream).await {
+                        Ok(Some(StreamPollReturn::Ok(chunk))) => {
+                            let finished = chunk.is_final();
+                            (chunk.into_events(), finished)
+                        }
+                        Ok(Some(StreamPollReturn::Err(json)))
+                        | Err(StreamError::StreamRecievedErr(json)) => {
+                            let err = ProviderError::from_body(None, json.to_string());
+                            (vec![StreamEvent::Error(err)], false)
+                        }
+                        Ok(None) => (vec![], true),
+                        Err(err) => {
+                            let _ = tx.send(Err(err)).await;
                             break;
                         }
+                    };
+                let ends = finished
+                    || events
+                        .last()
+                        .is_some_and(|event| matches!(event, StreamEvent::Error(_)));
+                for event in events {
+                    tracing::info!("Got event: {:?}", event);
+                    if tx
+                        .send(Ok(StreamThreadMessage::Event(event)))
+                        .await
+                        .is_err()
+                    {
+                        return;
                     }
-                    Err(err) => {
-                        tx.send(Err(err))
-                            .await
-                            .expect("failed to send error message back");
-                        break;
-                    }
-                };
+                }
+                if finished {
+                    let _ = tx.send(Ok(StreamThreadMessage::Finished)).await;
+                }
+                if ends {
+                    break;
+                }
             }
             tracing::info!("outside of loop");
-            return Ok::<(), StreamError>(());
         });
+        self.task = Some(task.abort_handle());
 
         Ok(())
     }
 }
 
+impl<T> Stream for StreamedCompletionHandler<T>
+where
+    T: StreamResponse,
+{
+    type Item = StreamResult<StreamEvent>;
+
+    /// The reading task is spawned on the first poll
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        if self.sender.is_some() && self.stream.is_some() {
+            if let Err(err) = self.spawn() {
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
+        loop {
+            let message = match self.receiver.poll_recv(cx) {
+                Poll::Ready(message) => message,
+                Poll::Pending => return Poll::Pending,
+            };
+            let event = match message {
+                Some(Ok(StreamThreadMessage::Event(StreamEvent::TextDelta(text)))) => {
+                    if text.is_empty() {
+                        continue;
+                    }
+                    self.message_content.push_str(&text);
+                    StreamEvent::TextDelta(text)
+                }
+                Some(Ok(StreamThreadMessage::Event(StreamEvent::Usage(usage)))) => {
+                    let total = self.usage.get_or_insert_with(TokenUsage::default);
+                    total.update(usage);
+                    StreamEvent::Usage(*total)
+                }
+                Some(Ok(StreamThreadMessage::Event(StreamEvent::ToolCallDelta {
+                    index,
+                    id,
+                    name,
+                    arguments,
+                }))) => {
+                    self.buffer_tool_call(index, &id, &name, &arguments);
+                    StreamEvent::ToolCallDelta {
+                        index,
+                        id,
+                        name,
+                        arguments,
+                    }
+                }
+                Some(Ok(StreamThreadMessage::Event(event))) => event,
+                Some(Ok(StreamThreadMessage::Finished)) => {
+                    self.finished = true;
+                    self.settle();
+                    let calls: Vec<StreamResult<StreamEvent>> = self
+                        .tool_calls
+                        .values()
+                        .map(|call| call.parse().map(StreamEvent::ToolCall))
+                        .collect();
+                    self.pending.extend(calls);
+                    return Poll::Ready(self.pending.pop_front());
+                }
+                None => {
+                    self.finished = true;
+                    self.settle();
+                    return Poll::Ready(None);
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+            };
+            return Poll::Ready(Some(Ok(event)));
+        }
+    }
+}
+
 pub enum StreamPollReturn<T> {
     Ok(T),
     Err(serde_json::Value),
@@ -224,7 +579,8 @@ impl CompletionStreamingThread {
     where
         T: StreamResponse,
     {
-        while let Some(Ok(stream_response)) = stream.next().await {
+        while let Some(stream_response) = stream.next().await {
+            let stream_response = stream_response?;
             warn!("Stream response json: {:?}", stream_response);
             match serde_json::from_value::<T>(stream_response.clone()) {
                 Ok(val) => return Ok(Some(StreamPollReturn::from(val))),
@@ -238,3 +594,108 @@ impl CompletionStreamingThread {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::convert::Infallible;
+
+    fn handler<T: StreamResponse>(transcript: &'static str) -> StreamedCompletionHandler<T> {
+        let body = futures::stream::iter([Ok::<_, Infallible>(Bytes::from_static(
+            transcript.as_bytes(),
+        ))]);
+        StreamedCompletionHandler::from(sse::sse_json_stream(body))
+    }
+
+    #[tokio::test]
+    async fn anthropic_stream_becomes_typed_events() {
+        let events: Vec<StreamEvent> = handler::<AnthropicStreamResponse>(include_str!(
+            "../../../../tests/transcripts/anthropic_stream.sse"
+        ))
+        .map(Result::unwrap)
+        .collect()
+        .await;
+        assert_eq!(
+            events,
+            [
+                StreamEvent::Usage(TokenUsage::new(25, 1)),
+                StreamEvent::Ping,
+                StreamEvent::TextDelta("Hello".to_owned()),
+                StreamEvent::TextDelta("!".to_owned()),
+                StreamEvent::Stop("end_turn".to_owned()),
+                StreamEvent::Usage(TokenUsage::new(25, 15)),
+            ]
+        );
+
+        let mut overloaded = handler::<AnthropicStreamResponse>(include_str!(
+            "../../../../tests/transcripts/anthropic_overloaded.sse"
+        ));
+        assert!(matches!(
+            overloaded.next().await,
+            Some(Ok(StreamEvent::Usage(_)))
+        ));
+        match overloaded.next().await {
+            Some(Ok(StreamEvent::Error(err))) => {
+                assert_eq!(err.error_type.as_deref(), Some("overloaded_error"))
+            }
+            other => panic!("expected an error event, got {:?}", other),
+        }
+        assert!(overloaded.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn streamed_tool_call_is_parsed_once_finished() {
+        let events: Vec<StreamEvent> = handler::<AnthropicStreamResponse>(include_str!(
+            "../../../../tests/transcripts/anthropic_tool_stream.sse"
+        ))
+        .map(Result::unwrap)
+        .collect()
+        .await;
+        let fragments: String = events
+            .iter()
+            .filter_map(|event| match event {
+                StreamEvent::ToolCallDelta { arguments, .. } => Some(arguments.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fragments, "{\"location\": \"San Francisco, CA\"}");
+        assert_eq!(
+            events.last(),
+            Some(&StreamEvent::ToolCall(ToolCall {
+                id: "toolu_01T1x1fJ34qAmk2tNTrN7Up6".to_owned(),
+                name: "get_weather".to_owned(),
+                arguments: serde_json::json!({"location": "San Francisco, CA"}),
+            }))
+        );
+
+        // Arguments that were cut off can not be parsed
+        let truncated = r#"event: content_block_start
+data: {"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"toolu_1","name":"f","input":{}}}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":0,"delta":{"type":"input_json_delta","partial_json":"{\"a"}}
+
+event: message_stop
+data: {"type":"message_stop"}
+
+"#;
+        let events: Vec<StreamResult<StreamEvent>> = handler::<AnthropicStreamResponse>(truncated)
+            .collect()
+            .await;
+        assert!(matches!(events.last(), Some(Err(StreamError::Json(_)))));
+    }
+
+    #[tokio::test]
+    async fn dropping_handler_aborts_stream_task() {
+        let stream: CompletionStream = Box::new(futures::stream::pending());
+        let mut handler = StreamedCompletionHandler::<OllamaStreamResponse>::from(stream);
+        handler.spawn().unwrap();
+        let task = handler.task.clone().unwrap();
+        assert!(!task.is_finished());
+
+        drop(handler);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(task.is_finished());
+    }
+}
@@ -0,0 +1,538 @@
+use super::{ProviderStreamHandler, StreamError, StreamEvent, StreamResult};
+use futures::{Stream, StreamExt};
+use serde_json::{Map, Number, Value};
+use std::{
+    iter::Peekable,
+    pin::Pin,
+    str::Chars,
+    task::{Context, Poll},
+};
+
+/// Arrays & objects nested deeper than this are rejected, like `serde_json` does
+const MAX_DEPTH: usize = 128;
+
+/// Buffers longer than this are only reparsed each time they have doubled in length, so long
+/// streams don't cost quadratic time
+const REPARSE_EVERY_FRAGMENT_UP_TO: usize = 8 * 1024;
+
+/// Best effort parse of JSON that may have been cut off anywhere, such as a model's answer
+/// part way through a stream. Open strings, arrays & objects are closed, while a key without a
+/// value or a literal like `tru` is dropped. `None` if nothing can be read yet or `input` is not
+/// the start of valid JSON, including JSON nested more than 128 levels deep
+pub fn parse_partial_json(input: &str) -> Option<Value> {
+    let mut parser = PartialParser {
+        chars: input.chars().peekable(),
+        depth: 0,
+    };
+    let value = parser.value().ok()??;
+    parser.skip_whitespace();
+    match parser.chars.peek() {
+        None => Some(value),
+        Some(_) => None,
+    }
+}
+
+/// Buffers JSON text as it is streamed, reparsing it with `parse_partial_json` after every
+/// fragment. Past 8KiB it is only reparsed each time it doubles, call `finish` once the text is
+/// complete to parse the rest
+#[derive(Debug, Clone, Default)]
+pub struct PartialJson {
+    buffer: String,
+    value: Option<Value>,
+    /// Length of the buffer when it was last parsed
+    parsed_len: usize,
+}
+
+impl PartialJson {
+    /// Adds a fragment, returns `true` if the parsed value changed
+    pub fn push(&mut self, fragment: &str) -> bool {
+        self.buffer.push_str(fragment);
+        if self.buffer.len() > REPARSE_EVERY_FRAGMENT_UP_TO
+            && self.buffer.len() < self.parsed_len * 2
+        {
+            return false;
+        }
+        self.parse()
+    }
+
+    /// Parses any text `push` skipped, returns `true` if the parsed value changed
+    pub fn finish(&mut self) -> bool {
+        self.parsed_len != self.buffer.len() && self.parse()
+    }
+
+    fn parse(&mut self) -> bool {
+        self.parsed_len = self.buffer.len();
+        match parse_partial_json(&self.buffer) {
+            Some(value) if self.value.as_ref() != Some(&value) => {
+                self.value = Some(value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The value of the text so far, kept from the last fragment that could be parsed
+    pub fn value(&self) -> Option<&Value> {
+        self.value.as_ref()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.buffer
+    }
+}
+
+/// The input can never become valid JSON
+struct Invalid;
+
+struct PartialParser<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// Arrays & objects currently open
+    depth: usize,
+}
+
+impl PartialParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    /// `None` if the input ended before anything of the value could be read
+    fn value(&mut self) -> Result<Option<Value>, Invalid> {
+        self.skip_whitespace();
+        let Some(&c) = self.chars.peek() else {
+            return Ok(None);
+        };
+        match c {
+            '{' => self.nested(Self::object).map(Some),
+            '[' => self.nested(Self::array).map(Some),
+            '"' => Ok(Some(Value::String(self.string()?.unwrap_or_else(|s| s)))),
+            '-' | '0'..='9' => self.number(),
+            _ => self.literal(),
+        }
+    }
+
+    /// Parses an array or object, `Invalid` past `MAX_DEPTH` so deep input can't overflow the
+    /// stack
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Value, Invalid>) -> Result<Value, Invalid> {
+        if self.depth == MAX_DEPTH {
+            return Err(Invalid);
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> Result<Value, Invalid> {
+        self.chars.next();
+        let mut object = Map::new();
+        loop {
+            self.skip_whitespace();
+            let key = match self.chars.peek() {
+                None => break,
+                Some('}') => {
+                    self.chars.next();
+                    break;
+                }
+                Some('"') => match self.string()? {
+                    Ok(key) => key,
+                    Err(_) => break,
+                },
+                Some(_) => return Err(Invalid),
+            };
+            self.skip_whitespace();
+            match self.chars.next() {
+                None => break,
+                Some(':') => {}
+                Some(_) => return Err(Invalid),
+            }
+            match self.value()? {
+                Some(value) => object.insert(key, value),
+                None => break,
+            };
+            self.skip_whitespace();
+            match self.chars.next() {
+                None | Some('}') => break,
+                Some(',') => {}
+                Some(_) => return Err(Invalid),
+            }
+        }
+        Ok(Value::Object(object))
+    }
+
+    fn array(&mut self) -> Result<Value, Invalid> {
+        self.chars.next();
+        let mut array = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.chars.next_if_eq(&']').is_some() {
+                break;
+            }
+            match self.value()? {
+                Some(value) => array.push(value),
+                None => break,
+            }
+            self.skip_whitespace();
+            match self.chars.next() {
+                None | Some(']') => break,
+                Some(',') => {}
+                Some(_) => return Err(Invalid),
+            }
+        }
+        Ok(Value::Array(array))
+    }
+
+    /// `Err` holds what was read of a string the input ended in, without any unfinished escape
+    fn string(&mut self) -> Result<Result<String, String>, Invalid> {
+        self.chars.next();
+        let mut string = String::new();
+        while let Some(c) = self.chars.next() {
+            match c {
+                '"' => return Ok(Ok(string)),
+                '\\' => match self.escape()? {
+                    Some(c) => string.push(c),
+                    None => break,
+                },
+                c if c < '\u{20}' => return Err(Invalid),
+                c => string.push(c),
+            }
+        }
+        Ok(Err(string))
+    }
+
+    /// `None` if the input ended within the escape
+    fn escape(&mut self) -> Result<Option<char>, Invalid> {
+        let Some(c) = self.chars.next() else {
+            return Ok(None);
+        };
+        let escaped = match c {
+            '"' | '\\' | '/' => c,
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let Some(high) = self.hex()? else {
+                    return Ok(None);
+                };
+                if !(0xD800..0xDC00).contains(&high) {
+                    return Ok(Some(char::from_u32(high).unwrap_or('\u{fffd}')));
+                }
+                // A surrogate pair is only complete once its second half has arrived
+                match (self.chars.next(), self.chars.next()) {
+                    (Some('\\'), Some('u')) => {}
+                    (None, _) | (Some('\\'), None) => return Ok(None),
+                    _ => return Ok(Some('\u{fffd}')),
+                }
+                let Some(low) = self.hex()? else {
+                    return Ok(None);
+                };
+                if !(0xDC00..0xE000).contains(&low) {
+                    return Err(Invalid);
+                }
+                let code = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+                char::from_u32(code).unwrap_or('\u{fffd}')
+            }
+            _ => return Err(Invalid),
+        };
+        Ok(Some(escaped))
+    }
+
+    /// The 4 hex digits of a `\u` escape, `None` if the input ended within them
+    fn hex(&mut self) -> Result<Option<u32>, Invalid> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let Some(c) = self.chars.next() else {
+                return Ok(None);
+            };
+            code = code * 16 + c.to_digit(16).ok_or(Invalid)?;
+        }
+        Ok(Some(code))
+    }
+
+    /// Numbers the input ended within are read up to their last digit, so `-` alone is `None`
+    fn number(&mut self) -> Result<Option<Value>, Invalid> {
+        let mut number = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+        {
+            number.push(c);
+        }
+        if self.chars.peek().is_some() {
+            return match serde_json::from_str::<Number>(&number) {
+                Ok(number) => Ok(Some(Value::Number(number))),
+                Err(_) => Err(Invalid),
+            };
+        }
+        let number = number.trim_end_matches(|c: char| !c.is_ascii_digit());
+        Ok(serde_json::from_str::<Number>(number)
+            .ok()
+            .map(Value::Number))
+    }
+
+    /// A literal the input ended within, such as `tru`, is `None`
+    fn literal(&mut self) -> Result<Option<Value>, Invalid> {
+        let mut literal = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphabetic()) {
+            literal.push(c);
+        }
+        match literal.as_str() {
+            "true" => Ok(Some(Value::Bool(true))),
+            "false" => Ok(Some(Value::Bool(false))),
+            "null" => Ok(Some(Value::Null)),
+            partial
+                if self.chars.peek().is_none()
+                    && ["true", "false", "null"]
+                        .iter()
+                        .any(|literal| literal.starts_with(partial)) =>
+            {
+                Ok(None)
+            }
+            _ => Err(Invalid),
+        }
+    }
+}
+
+/// Adapts a stream of `StreamEvent`s into the best effort parsed JSON of the answer, yielding a
+/// new `Value` every time a delta changes it. Text deltas & the arguments of the first streamed
+/// tool call are read separately, so this works with both `Agent::stream_completion` &
+/// `Agent::stream_function_completion`. The exact arguments are yielded once the tool call is
+/// parsed, if they differ from the last value.
+/// Wrap `&mut ProviderStreamHandler` to commit the handler once the stream has ended
+#[derive(Debug)]
+pub struct PartialJsonStream<S = ProviderStreamHandler> {
+    inner: S,
+    text: PartialJson,
+    arguments: PartialJson,
+    /// Index of the tool call whose arguments are read
+    call_index: Option<usize>,
+    /// Id of that tool call, so only its parsed `ToolCall` is read. Without deltas carrying an id
+    /// the first `ToolCall` is read
+    call_id: Option<String>,
+    /// The inner stream has ended
+    done: bool,
+}
+
+impl<S> PartialJsonStream<S>
+where
+    S: Stream<Item = StreamResult<StreamEvent>> + Unpin,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            text: PartialJson::default(),
+            arguments: PartialJson::default(),
+            call_index: None,
+            call_id: None,
+            done: false,
+        }
+    }
+
+    /// The JSON streamed so far, the tool call arguments if any have been streamed
+    pub fn value(&self) -> Option<&Value> {
+        match self.call_index.is_some() || self.call_id.is_some() {
+            true => self.arguments.value(),
+            false => self.text.value(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl ProviderStreamHandler {
+    /// See `PartialJsonStream`
+    pub fn partial_json(self) -> PartialJsonStream {
+        PartialJsonStream::new(self)
+    }
+}
+
+impl<S> Stream for PartialJsonStream<S>
+where
+    S: Stream<Item = StreamResult<StreamEvent>> + Unpin,
+{
+    type Item = StreamResult<Value>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            let event = match this.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(event))) => event,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    // A long answer may end with text `push` hasn't parsed yet
+                    this.done = true;
+                    let json = match this.call_index.is_some() || this.call_id.is_some() {
+                        true => &mut this.arguments,
+                        false => &mut this.text,
+                    };
+                    let value = json.finish().then(|| json.value.clone()).flatten();
+                    return Poll::Ready(value.map(Ok));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            let changed = match event {
+                StreamEvent::TextDelta(text) => this.text.push(&text).then_some(&this.text),
+                StreamEvent::ToolCallDelta {
+                    index,
+                    id,
+                    arguments,
+                    ..
+                } if *this.call_index.get_or_insert(index) == index => {
+                    if let Some(id) = id {
+                        this.call_id.get_or_insert(id);
+                    }
+                    this.arguments.push(&arguments).then_some(&this.arguments)
+                }
+                StreamEvent::ToolCall(call)
+                    if *this.call_id.get_or_insert_with(|| call.id.clone()) == call.id =>
+                {
+                    if this.value() == Some(&call.arguments) {
+                        continue;
+                    }
+                    this.arguments.value = Some(call.arguments.clone());
+                    this.arguments.parsed_len = this.arguments.buffer.len();
+                    return Poll::Ready(Some(Ok(call.arguments)));
+                }
+                StreamEvent::Error(err) => {
+                    let json = serde_json::from_str(&err.body).unwrap_or(Value::String(err.body));
+                    return Poll::Ready(Some(Err(StreamError::from(json))));
+                }
+                _ => None,
+            };
+            if let Some(value) = changed.and_then(PartialJson::value) {
+                return Poll::Ready(Some(Ok(value.clone())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::functions::ToolCall;
+    use serde_json::json;
+
+    #[test]
+    fn truncated_json_is_closed() {
+        let cases = [
+            ("", None),
+            ("  ", None),
+            ("{", Some(json!({}))),
+            (r#"{"na"#, Some(json!({}))),
+            (r#"{"name""#, Some(json!({}))),
+            (r#"{"name": "#, Some(json!({}))),
+            (r#"{"name": "Jo"#, Some(json!({"name": "Jo"}))),
+            (r#"{"name": "Jo\"#, Some(json!({"name": "Jo"}))),
+            (r#"{"name": "Jo\u00e"#, Some(json!({"name": "Jo"}))),
+            (
+                r#"{"name": "Joé", "age": 4"#,
+                Some(json!({"name": "Joé", "age": 4})),
+            ),
+            (r#"{"emoji": "\ud83d"#, Some(json!({"emoji": ""}))),
+            (r#"{"emoji": "😀"}"#, Some(json!({"emoji": "😀"}))),
+            (r#"{"age": -"#, Some(json!({}))),
+            (r#"{"age": 4.5e"#, Some(json!({"age": 4.5}))),
+            (r#"{"ok": tr"#, Some(json!({}))),
+            (
+                r#"{"ok": true, "tags": ["a", "b"#,
+                Some(json!({"ok": true, "tags": ["a", "b"]})),
+            ),
+            (r#"{"tags": [1, "#, Some(json!({"tags": [1]}))),
+            (
+                r#"[{"a": null}, {"b": [[]"#,
+                Some(json!([{"a": null}, {"b": [[]]}])),
+            ),
+            (r#"{"a": 1}"#, Some(json!({"a": 1}))),
+            ("\"open", Some(json!("open"))),
+            (r#"{"a": 1}}"#, None),
+            (r#"{"a" 1"#, None),
+            (r#"[1 2"#, None),
+            (r#"{"a": "\q"#, None),
+            (r#"{"a": nope"#, None),
+            (r#"{"a": 1-2}"#, None),
+            (r#""\ud83d\u0041""#, None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_partial_json(input), expected, "parsing {:?}", input);
+        }
+    }
+
+    #[test]
+    fn nesting_is_capped() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse_partial_json(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(parse_partial_json(&nested(MAX_DEPTH + 1)), None);
+        assert_eq!(parse_partial_json(&"[".repeat(100_000)), None);
+    }
+
+    #[test]
+    fn long_buffers_are_reparsed_when_doubled() {
+        let mut json = PartialJson::default();
+        let long = "a".repeat(REPARSE_EVERY_FRAGMENT_UP_TO);
+        assert!(json.push(&format!("\"{long}")));
+        assert!(!json.push("b"));
+        assert_eq!(json.value(), Some(&Value::String(long.clone())));
+        assert!(json.finish());
+        assert!(!json.finish());
+        assert_eq!(json.value(), Some(&Value::String(format!("{long}b"))));
+
+        assert!(json.push(&long.repeat(2)));
+        assert_eq!(
+            json.value(),
+            Some(&Value::String(format!("{long}b{long}{long}")))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_value_is_yielded_whenever_it_changes() {
+        let deltas = [
+            "{\"loc",
+            "ation\": \"Par",
+            "is\"",
+            ", \"unit\": ",
+            "\"c",
+            "\"}",
+        ];
+        let mut events: Vec<StreamResult<StreamEvent>> = deltas
+            .iter()
+            .map(|arguments| {
+                Ok(StreamEvent::ToolCallDelta {
+                    index: 0,
+                    id: None,
+                    name: None,
+                    arguments: arguments.to_string(),
+                })
+            })
+            .collect();
+        events.insert(1, Ok(StreamEvent::TextDelta("ignored".to_owned())));
+        events.push(Ok(StreamEvent::ToolCall(ToolCall {
+            id: "call_1".to_owned(),
+            name: "get_weather".to_owned(),
+            arguments: json!({"location": "Paris", "unit": "c"}),
+        })));
+
+        let values: Vec<Value> = PartialJsonStream::new(futures::stream::iter(events))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(
+            values,
+            [
+                json!({}),
+                json!({"location": "Par"}),
+                json!({"location": "Paris"}),
+                json!({"location": "Paris", "unit": "c"}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use super::{CompletionStream, StreamError, StreamResult};
+use bytes::Bytes;
+use futures::{future, Stream, StreamExt};
+use serde_json::Value;
+use std::collections::VecDeque;
+
+/// Sent by OpenAi as the data of the last event
+const DONE: &str = "[DONE]";
+
+/// One event of a `text/event-stream` body
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The `event:` field, `None` for unnamed events which default to `message`
+    pub event: Option<String>,
+    /// Every `data:` line of the event joined by newlines
+    pub data: String,
+    pub id: Option<String>,
+}
+
+/// Incremental decoder of server sent events as specified by the HTML standard.
+/// Bytes may be split anywhere, including within a line or a multi byte character
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    /// Bytes of a line that has not ended yet
+    line: Vec<u8>,
+    /// The last byte was `\r`, so a `\n` right after it ends no other line
+    after_cr: bool,
+    /// Whether the first line has been read, it may start with a byte order mark
+    started: bool,
+    event: Option<String>,
+    /// Every `data:` line so far, each followed by a newline
+    data: String,
+    id: Option<String>,
+}
+
+impl SseDecoder {
+    /// Events completed by `bytes`
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        let mut events = vec![];
+        for &byte in bytes {
+            let after_cr = std::mem::take(&mut self.after_cr);
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    self.after_cr = byte == b'\r';
+                    let line = std::mem::take(&mut self.line);
+                    events.extend(self.read_line(&line));
+                }
+                _ => self.line.push(byte),
+            }
+        }
+        events
+    }
+
+    /// The last event if the body ended without the blank line that should follow it
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let line = std::mem::take(&mut self.line);
+        if !line.is_empty() {
+            let _ = self.read_line(&line);
+        }
+        self.dispatch()
+    }
+
+    fn read_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        let mut line = String::from_utf8_lossy(line);
+        if !std::mem::replace(&mut self.started, true) {
+            if let Some(stripped) = line.strip_prefix('\u{feff}') {
+                line = stripped.to_owned().into();
+            }
+        }
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        // Comments, often sent as keep alives
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_owned()),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.id = Some(value.to_owned()),
+            // `retry` & unknown fields are ignored
+            _ => {}
+        }
+        None
+    }
+
+    /// Events without data are dropped
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        let mut data = std::mem::take(&mut self.data);
+        data.pop()?;
+        Some(SseEvent {
+            event,
+            data,
+            id: self.id.clone(),
+        })
+    }
+}
+
+/// Decodes a body of server sent events
+pub fn sse_events<S, E>(body: S) -> impl Stream<Item = StreamResult<SseEvent>> + Send + Unpin
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+    E: Into<anyhow::Error> + Send + 'static,
+{
+    let state = (body, SseDecoder::default(), VecDeque::new(), false);
+    Box::pin(futures::stream::unfold(
+        state,
+        |(mut body, mut decoder, mut ready, mut ended)| async move {
+            loop {
+                if let Some(event) = ready.pop_front() {
+                    return Some((Ok(event), (body, decoder, ready, ended)));
+                }
+                if ended {
+                    return None;
+                }
+                match body.next().await {
+                    Some(Ok(bytes)) => ready.extend(decoder.feed(&bytes)),
+                    Some(Err(err)) => {
+                        let err = StreamError::Undefined(err.into());
+                        return Some((Err(err), (body, decoder, ready, true)));
+                    }
+                    None => {
+                        ended = true;
+                        ready.extend(decoder.finish());
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// The JSON data of each event, ending at OpenAi's `[DONE]`. `event: error` events & data
+/// holding an `error` object, as both OpenAi & Anthropic send mid stream, become
+/// `StreamError::StreamRecievedErr`
+pub fn sse_json_stream<S, E>(body: S) -> CompletionStream
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+    E: Into<anyhow::Error> + Send + 'static,
+{
+    Box::new(
+        sse_events(body)
+            .take_while(|event| future::ready(!matches!(event, Ok(e) if e.data == DONE)))
+            .map(|event| {
+                let event = event?;
+                let is_error = event.event.as_deref() == Some("error");
+                let json = match serde_json::from_str::<Value>(&event.data) {
+                    Ok(json) => json,
+                    Err(_) if is_error => Value::String(event.data),
+                    Err(err) => return Err(err.into()),
+                };
+                if is_error || json.get("error").is_some() {
+                    return Err(StreamError::from(json));
+                }
+                Ok(json)
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    const OPENAI: &str = include_str!("../../../../tests/transcripts/openai_stream.sse");
+    const ANTHROPIC: &str = include_str!("../../../../tests/transcripts/anthropic_stream.sse");
+    const ANTHROPIC_ERROR: &str =
+        include_str!("../../../../tests/transcripts/anthropic_overloaded.sse");
+
+    /// Every way of splitting `transcript` in two chunks, plus one byte at a time
+    fn splits(transcript: &str) -> Vec<Vec<Bytes>> {
+        let bytes = transcript.as_bytes();
+        let mut splits: Vec<Vec<Bytes>> = (0..=bytes.len())
+            .map(|at| {
+                vec![
+                    Bytes::copy_from_slice(&bytes[..at]),
+                    Bytes::copy_from_slice(&bytes[at..]),
+                ]
+            })
+            .collect();
+        splits.push(
+            bytes
+                .iter()
+                .map(|b| Bytes::copy_from_slice(&[*b]))
+                .collect(),
+        );
+        splits
+    }
+
+    async fn decode(chunks: Vec<Bytes>) -> Vec<StreamResult<Value>> {
+        let body = futures::stream::iter(chunks.into_iter().map(Ok::<_, Infallible>));
+        sse_json_stream(body).collect().await
+    }
+
+    #[test]
+    fn events_are_decoded_however_bytes_are_split() {
+        let transcript = "\u{feff}: keep alive\r\nevent: note\r\ndata: first\r\ndata:  second\r\nid: 7\r\n\r\ndata\n\nretry: 100\nevent: empty\n\ndata: héllo\rdata: tail";
+        let expected = vec![
+            SseEvent {
+                event: Some("note".to_owned()),
+                data: "first\n second".to_owned(),
+                id: Some("7".to_owned()),
+            },
+            SseEvent {
+                event: None,
+                data: String::new(),
+                id: Some("7".to_owned()),
+            },
+            SseEvent {
+                event: None,
+                data: "héllo\ntail".to_owned(),
+                id: Some("7".to_owned()),
+            },
+        ];
+        for chunks in splits(transcript) {
+            let mut decoder = SseDecoder::default();
+            let mut events: Vec<SseEvent> = chunks.iter().flat_map(|c| decoder.feed(c)).collect();
+            events.extend(decoder.finish());
+            assert_eq!(events, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn recorded_transcripts_are_decoded() {
+        for chunks in splits(OPENAI) {
+            let chunks: Vec<Value> = decode(chunks)
+                .await
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(chunks.len(), 4);
+            assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "Hello");
+            assert_eq!(chunks[3]["usage"]["total_tokens"], 21);
+        }
+
+        for chunks in splits(ANTHROPIC) {
+            let types: Vec<String> = decode(chunks)
+                .await
+                .into_iter()
+                .map(|chunk| chunk.unwrap()["type"].as_str().unwrap().to_owned())
+                .collect();
+            assert_eq!(
+                types,
+                [
+                    "message_start",
+                    "content_block_start",
+                    "ping",
+                    "content_block_delta",
+                    "content_block_delta",
+                    "content_block_stop",
+                    "message_delta",
+                    "message_stop"
+                ]
+            );
+        }
+
+        let chunks = decode(vec![Bytes::from_static(ANTHROPIC_ERROR.as_bytes())]).await;
+        assert!(chunks[0].is_ok());
+        match &chunks[1] {
+            Err(StreamError::StreamRecievedErr(json)) => {
+                assert_eq!(json["error"]["type"], "overloaded_error")
+            }
+            other => panic!("expected a provider error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, AddAssign};
+
+/// Tokens consumed by a single completion, as reported by the provider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    /// Tokens in the messages sent to the model
+    pub prompt_tokens: u32,
+    /// Tokens generated by the model
+    pub completion_tokens: u32,
+}
+
+impl TokenUsage {
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Providers report running counts while streaming, so a later report replaces any field it
+    /// sets rather than adding to it
+    pub(crate) fn update(&mut self, other: TokenUsage) {
+        if other.prompt_tokens > 0 {
+            self.prompt_tokens = other.prompt_tokens;
+        }
+        if other.completion_tokens > 0 {
+            self.completion_tokens = other.completion_tokens;
+        }
+    }
+}
+
+impl Add for TokenUsage {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            prompt_tokens: self.prompt_tokens + rhs.prompt_tokens,
+            completion_tokens: self.completion_tokens + rhs.completion_tokens,
+        }
+    }
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+/// Cumulative token usage & estimated cost of every completion an `Agent` has made, including
+/// the embeddings its semantic cache made for them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTracker {
+    total: TokenUsage,
+    last: Option<TokenUsage>,
+    completions: u32,
+    #[serde(default)]
+    cost: f64,
+}
+
+impl UsageTracker {
+    /// Record a finished completion. `usage` is `None` when the provider did not report any,
+    /// `cost` is `None` when the model has no known price
+    pub(crate) fn record(&mut self, usage: Option<TokenUsage>, cost: Option<f64>) {
+        self.completions += 1;
+        if let Some(usage) = usage {
+            self.total += usage;
+        }
+        self.last = usage;
+        self.cost += cost.unwrap_or_default();
+    }
+
+    /// Record the tokens an embedding used, which count towards the total & cost but are not a
+    /// completion
+    pub(crate) fn record_embedding(&mut self, usage: Option<TokenUsage>, cost: Option<f64>) {
+        if let Some(usage) = usage {
+            self.total += usage;
+        }
+        self.cost += cost.unwrap_or_default();
+    }
+
+    /// Sum of every recorded completion's & embedding's usage
+    pub fn total(&self) -> TokenUsage {
+        self.total
+    }
+
+    /// Usage of the most recent completion, if the provider reported it
+    pub fn last(&self) -> Option<TokenUsage> {
+        self.last
+    }
+
+    /// Number of completions recorded, including ones without reported usage
+    pub fn completions(&self) -> u32 {
+        self.completions
+    }
+
+    /// Estimated cost in US dollars of every priced completion
+    pub fn cost(&self) -> f64 {
+        self.cost
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_tracker_accumulates() {
+        let mut tracker = UsageTracker::default();
+        tracker.record(Some(TokenUsage::new(10, 5)), Some(0.5));
+        tracker.record(None, None);
+        tracker.record(Some(TokenUsage::new(3, 2)), None);
+        tracker.record_embedding(Some(TokenUsage::new(4, 0)), Some(0.25));
+
+        assert_eq!(tracker.total(), TokenUsage::new(17, 7));
+        assert_eq!(tracker.total().total_tokens(), 24);
+        assert_eq!(tracker.last(), Some(TokenUsage::new(3, 2)));
+        assert_eq!(tracker.completions(), 3);
+        assert_eq!(tracker.cost(), 0.75);
+
+        tracker.reset();
+        assert_eq!(tracker, UsageTracker::default());
+    }
+
+    #[test]
+    fn streamed_usage_updates_replace_running_counts() {
+        let mut usage = TokenUsage::default();
+        usage.update(TokenUsage::new(25, 1));
+        usage.update(TokenUsage::new(0, 15));
+        assert_eq!(usage, TokenUsage::new(25, 15));
+    }
+}
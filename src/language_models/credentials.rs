@@ -0,0 +1,218 @@
+use super::secret::SecretString;
+use crate::errors::error_chain_fmt;
+use reqwest::header::HeaderValue;
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+pub type CredentialResult<T> = Result<T, CredentialError>;
+
+#[derive(thiserror::Error)]
+pub enum CredentialError {
+    #[error(transparent)]
+    Undefined(#[from] anyhow::Error),
+    /// The environment variable is not set or is blank
+    MissingEnvVar(String),
+    /// The key file could not be read
+    File {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// A `RoundRobinKeys` was given no keys
+    NoKeys,
+    /// The key holds characters a header cannot, such as a newline
+    InvalidKey,
+}
+
+impl Debug for CredentialError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Display for CredentialError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let display = match self {
+            Self::Undefined(err) => err.to_string(),
+            Self::MissingEnvVar(var) => format!("Environment variable {} is not set", var),
+            Self::File { path, .. } => format!("Could not read key file {:?}", path),
+            Self::NoKeys => "No api keys to choose from".to_string(),
+            Self::InvalidKey => "Api key is not a valid header value".to_string(),
+        };
+        write!(f, "{}", display)
+    }
+}
+
+/// Where a model gets its api key from. Keys are resolved before every request, so a provider
+/// can rotate keys without the model being rebuilt
+pub trait CredentialProvider: Debug + Send + Sync {
+    fn api_key(&self) -> CredentialResult<SecretString>;
+}
+
+/// A static key
+impl CredentialProvider for SecretString {
+    fn api_key(&self) -> CredentialResult<SecretString> {
+        Ok(self.clone())
+    }
+}
+
+/// Reads the key from an environment variable on every request, surrounding whitespace is ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVarKey(String);
+
+impl EnvVarKey {
+    pub fn new(var: &str) -> Self {
+        Self(var.to_owned())
+    }
+}
+
+impl CredentialProvider for EnvVarKey {
+    fn api_key(&self) -> CredentialResult<SecretString> {
+        match std::env::var(&self.0) {
+            Ok(key) if !key.trim().is_empty() => Ok(key.trim().into()),
+            _ => Err(CredentialError::MissingEnvVar(self.0.clone())),
+        }
+    }
+}
+
+/// Reads the key from a file on every request, surrounding whitespace is ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileKey(PathBuf);
+
+impl FileKey {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self(path.into())
+    }
+}
+
+impl CredentialProvider for FileKey {
+    fn api_key(&self) -> CredentialResult<SecretString> {
+        std::fs::read_to_string(&self.0)
+            .map(|key| key.trim().into())
+            .map_err(|source| CredentialError::File {
+                path: self.0.clone(),
+                source,
+            })
+    }
+}
+
+/// Gets the key from a callback on every request, for keys fetched from a vault or refreshed
+/// by the application
+#[derive(Clone)]
+pub struct KeyCallback(Arc<dyn Fn() -> CredentialResult<SecretString> + Send + Sync>);
+
+impl KeyCallback {
+    pub fn new(
+        callback: impl Fn() -> CredentialResult<SecretString> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(callback))
+    }
+}
+
+impl Debug for KeyCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "KeyCallback")
+    }
+}
+
+impl CredentialProvider for KeyCallback {
+    fn api_key(&self) -> CredentialResult<SecretString> {
+        (self.0)()
+    }
+}
+
+/// Hands out each of its keys in turn, spreading requests across them.
+/// Each key has its own `RateLimit` allowance
+#[derive(Debug)]
+pub struct RoundRobinKeys {
+    keys: Vec<SecretString>,
+    next: AtomicUsize,
+}
+
+impl RoundRobinKeys {
+    pub fn new(keys: impl IntoIterator<Item = impl Into<SecretString>>) -> Self {
+        Self {
+            keys: keys.into_iter().map(Into::into).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl CredentialProvider for RoundRobinKeys {
+    fn api_key(&self) -> CredentialResult<SecretString> {
+        if self.keys.is_empty() {
+            return Err(CredentialError::NoKeys);
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Ok(self.keys[next % self.keys.len()].clone())
+    }
+}
+
+/// The credential provider of a model, clones share the same provider. Defaults to an empty key
+#[derive(Debug, Clone)]
+pub struct Credentials(Arc<dyn CredentialProvider>);
+
+impl Credentials {
+    pub fn new(provider: impl CredentialProvider + 'static) -> Self {
+        Self(Arc::new(provider))
+    }
+
+    /// Errors if the key could not be sent in a header
+    pub(crate) fn resolve(&self) -> CredentialResult<SecretString> {
+        let key = self.0.api_key()?;
+        match HeaderValue::from_str(key.expose()) {
+            Ok(_) => Ok(key),
+            Err(_) => Err(CredentialError::InvalidKey),
+        }
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::new(SecretString::default())
+    }
+}
+
+impl<T: Into<SecretString>> From<T> for Credentials {
+    fn from(key: T) -> Self {
+        Self::new(key.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_resolved_per_request() {
+        let keys = RoundRobinKeys::new(["first", "second"]);
+        let resolved: Vec<String> = (0..3)
+            .map(|_| keys.api_key().unwrap().expose().to_owned())
+            .collect();
+        assert_eq!(resolved, ["first", "second", "first"]);
+        assert!(matches!(
+            RoundRobinKeys::new(Vec::<String>::new()).api_key(),
+            Err(CredentialError::NoKeys)
+        ));
+
+        let path = std::env::temp_dir().join(format!("espionox-key-{}", uuid::Uuid::new_v4()));
+        let file = FileKey::new(&path);
+        assert!(matches!(file.api_key(), Err(CredentialError::File { .. })));
+        std::fs::write(&path, "sk-from-file\n").unwrap();
+        assert_eq!(file.api_key().unwrap().expose(), "sk-from-file");
+        std::fs::write(&path, "sk-rotated").unwrap();
+        assert_eq!(file.api_key().unwrap().expose(), "sk-rotated");
+        let _ = std::fs::remove_file(path);
+
+        let missing = EnvVarKey::new("ESPIONOX_TEST_KEY_THAT_IS_NEVER_SET");
+        assert!(matches!(
+            missing.api_key(),
+            Err(CredentialError::MissingEnvVar(_))
+        ));
+    }
+}
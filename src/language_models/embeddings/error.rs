@@ -1,4 +1,7 @@
-use crate::errors::error_chain_fmt;
+use crate::{
+    errors::error_chain_fmt,
+    language_models::{completions::error::ProviderError, credentials::CredentialError},
+};
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
 pub type EmbeddingResult<T> = Result<T, EmbeddingError>;
@@ -9,6 +12,9 @@ pub enum EmbeddingError {
     Undefined(#[from] anyhow::Error),
     Json(#[from] serde_json::Error),
     Request(#[from] reqwest::Error),
+    Provider(#[from] ProviderError),
+    /// The model's api key could not be resolved
+    Credentials(#[from] CredentialError),
 }
 
 impl Debug for EmbeddingError {
@@ -23,6 +29,8 @@ impl Display for EmbeddingError {
             Self::Json(err) => err.to_string(),
             Self::Undefined(err) => err.to_string(),
             Self::Request(err) => err.to_string(),
+            Self::Provider(err) => format!("Provider error: {}", err),
+            Self::Credentials(err) => format!("Could not get api key: {}", err),
         };
         write!(f, "{}", display)
     }
@@ -1,11 +1,14 @@
 use super::error::EmbeddingResult;
+use crate::language_models::completions::usage::TokenUsage;
 use futures::Future;
 use reqwest::{header::HeaderMap, Response};
 use serde_json::Value;
 use std::{fmt::Debug, pin::Pin};
 
-pub type ProcessEmbeddingResponseReturn<'r> =
-    Pin<Box<dyn Future<Output = EmbeddingResult<Vec<f32>>> + Send + Sync + 'r>>;
+/// The embedding & the tokens it used, if the provider reports them
+pub type ProcessEmbeddingResponseReturn<'r> = Pin<
+    Box<dyn Future<Output = EmbeddingResult<(Vec<f32>, Option<TokenUsage>)>> + Send + Sync + 'r>,
+>;
 pub trait EmbeddingRequest: Debug + Sync + Send + 'static {
     fn headers(&self, api_key: &str) -> HeaderMap;
     fn model_str(&self) -> &str;
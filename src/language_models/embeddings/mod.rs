@@ -1,20 +1,47 @@
-use self::{error::EmbeddingResult, inference::EmbeddingRequest, openai::OpenAiEmbeddingModel};
+use self::{
+    error::EmbeddingResult, inference::EmbeddingRequest, ollama::OllamaEmbeddingModel,
+    openai::OpenAiEmbeddingModel,
+};
+use super::{
+    cache::ResponseCache,
+    completions::usage::TokenUsage,
+    credentials::{CredentialProvider, Credentials},
+    rate_limit::{estimate_tokens, RateLimit, Throttle},
+    retry::RetryPolicy,
+    secret::SecretString,
+    transport::default_client,
+};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
 pub mod error;
 pub mod inference;
+pub mod ollama;
 pub mod openai;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EmbeddingProvider {
     OpenAi(OpenAiEmbeddingModel),
+    Ollama(OllamaEmbeddingModel),
+}
+
+impl From<OpenAiEmbeddingModel> for EmbeddingProvider {
+    fn from(value: OpenAiEmbeddingModel) -> Self {
+        Self::OpenAi(value)
+    }
+}
+
+impl From<OllamaEmbeddingModel> for EmbeddingProvider {
+    fn from(value: OllamaEmbeddingModel) -> Self {
+        Self::Ollama(value)
+    }
 }
 
 impl EmbeddingProvider {
-    fn inner_request(&self) -> Box<&dyn EmbeddingRequest> {
+    fn inner_request(&self) -> &dyn EmbeddingRequest {
         match &self {
-            Self::OpenAi(b) => return Box::new(b),
+            Self::OpenAi(b) => b,
+            Self::Ollama(b) => b,
         }
     }
 }
@@ -22,32 +49,138 @@ impl EmbeddingProvider {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmbeddingModel {
     provider: EmbeddingProvider,
-    api_key: String,
+    /// Resolved before every request. Never serialized, supply it again after deserializing with
+    /// `with_api_key` or `with_credentials`
+    #[serde(skip)]
+    credentials: Credentials,
+    #[serde(default)]
+    retry_policy: RetryPolicy,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
     #[serde(skip)]
+    cache: Option<ResponseCache>,
+    #[serde(skip, default = "default_client")]
     client: Client,
 }
 
 impl EmbeddingModel {
+    pub fn new(provider: impl Into<EmbeddingProvider>, api_key: &str) -> Self {
+        Self {
+            provider: provider.into(),
+            credentials: api_key.into(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            cache: None,
+            client: default_client(),
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: impl Into<SecretString>) -> Self {
+        self.credentials = Credentials::new(api_key.into());
+        self
+    }
+
+    pub fn with_credentials(mut self, credentials: impl CredentialProvider + 'static) -> Self {
+        self.credentials = Credentials::new(credentials);
+        self
+    }
+
+    /// Send requests with `client` instead of the `default_client`, see `TransportConfig`
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn set_client(&mut self, client: Client) {
+        self.client = client;
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     pub fn default_openai(api_key: &str) -> Self {
-        let client = Client::new();
+        let client = default_client();
         Self {
             provider: EmbeddingProvider::OpenAi(OpenAiEmbeddingModel::default()),
-            api_key: api_key.to_owned(),
+            credentials: api_key.into(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            cache: None,
             client,
         }
     }
 
+    /// Local Ollama embedding model for the given model tag, Ollama needs no api key
+    pub fn default_ollama(model: &str) -> Self {
+        Self::new(OllamaEmbeddingModel::new(model), "")
+    }
+
+    /// The model identifier sent to the provider
+    pub fn model_str(&self) -> &str {
+        self.provider.inner_request().model_str()
+    }
+
     pub async fn get_embedding(&self, text: &str) -> EmbeddingResult<Vec<f32>> {
+        Ok(self.embed(text, true).await?.0)
+    }
+
+    /// Skips reading the cache, the fresh embedding still replaces the cached one
+    pub async fn get_embedding_uncached(&self, text: &str) -> EmbeddingResult<Vec<f32>> {
+        Ok(self.embed(text, false).await?.0)
+    }
+
+    /// The embedding of `text` & the tokens the provider reported, `None` for cached embeddings
+    pub(crate) async fn embed(
+        &self,
+        text: &str,
+        read_cache: bool,
+    ) -> EmbeddingResult<(Vec<f32>, Option<TokenUsage>)> {
         let request = self.provider.inner_request();
-        let headers = request.headers(&self.api_key);
+        let api_key = self.credentials.resolve()?;
+        let headers = request.headers(api_key.expose());
         let url = request.url_str();
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .json(&request.as_json(text)?)
-            .send()
+        let body = request.as_json(text)?;
+        let cached = self
+            .cache
+            .as_ref()
+            .map(|cache| (cache, ResponseCache::key(url, &body)));
+        if let Some((cache, key)) = cached.as_ref().filter(|_| read_cache) {
+            if let Some(embedding) = cache.get(key) {
+                return Ok((embedding, None));
+            }
+        }
+        let throttle = self.rate_limit.as_ref().map(|limit| Throttle {
+            limit,
+            url,
+            api_key: api_key.expose(),
+            tokens: estimate_tokens(&text, None),
+        });
+        let (response, permit) = self
+            .retry_policy
+            .send(
+                || self.client.post(url).headers(headers.clone()).json(&body),
+                throttle.as_ref(),
+            )
             .await?;
-        Ok(request.process_response(response).await?)
+        let (embedding, usage) = request.process_response(response).await?;
+        if let Some(permit) = permit {
+            permit.settle(usage);
+        }
+        if let Some((cache, key)) = cached {
+            cache.insert(&key, &embedding);
+        }
+        Ok((embedding, usage))
     }
 }
@@ -0,0 +1,68 @@
+use super::{
+    error::EmbeddingResult,
+    inference::{EmbeddingRequest, ProcessEmbeddingResponseReturn},
+};
+use crate::language_models::completions::ollama::builder::DEFAULT_OLLAMA_HOST;
+use anyhow::anyhow;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// An embedding model served by a local Ollama instance through its `/api/embeddings` endpoint
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OllamaEmbeddingModel {
+    url: String,
+    model: String,
+}
+
+impl OllamaEmbeddingModel {
+    /// `model` is any embedding model pulled into Ollama, for example `nomic-embed-text`
+    pub fn new(model: &str) -> Self {
+        Self::with_host(DEFAULT_OLLAMA_HOST, model)
+    }
+
+    pub fn with_host(host: &str, model: &str) -> Self {
+        Self {
+            url: format!("{}/api/embeddings", host.trim_end_matches('/')),
+            model: model.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum OllamaEmbeddingResponse {
+    Success { embedding: Vec<f32> },
+    Err { error: String },
+}
+
+impl EmbeddingRequest for OllamaEmbeddingModel {
+    fn model_str(&self) -> &str {
+        &self.model
+    }
+    fn url_str(&self) -> &str {
+        &self.url
+    }
+    fn headers(&self, _api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("Content-Type", "application/json".parse().unwrap());
+        map
+    }
+    fn as_json(&self, text: &str) -> EmbeddingResult<Value> {
+        Ok(json!({ "model": self.model_str(), "prompt": text }))
+    }
+    fn process_response<'r>(
+        &'r self,
+        response: reqwest::Response,
+    ) -> ProcessEmbeddingResponseReturn<'r> {
+        Box::pin(async {
+            let json = response.json().await?;
+            match serde_json::from_value(json)? {
+                OllamaEmbeddingResponse::Success { embedding } => Ok((embedding, None)),
+                OllamaEmbeddingResponse::Err { error } => {
+                    Err(anyhow!("Ollama embedding error: {}", error).into())
+                }
+            }
+        })
+    }
+}
@@ -1,5 +1,7 @@
 use super::inference::EmbeddingRequest;
-use crate::language_models::completions::openai::requests::OpenAiUsage;
+use crate::language_models::{
+    completions::openai::requests::OpenAiUsage, secret::sensitive_header,
+};
 use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -36,10 +38,9 @@ impl EmbeddingRequest for OpenAiEmbeddingModel {
     }
     fn headers(&self, api_key: &str) -> HeaderMap {
         let mut map = HeaderMap::new();
-        map.insert(
-            "Authorization",
-            format!("Bearer {}", api_key).parse().unwrap(),
-        );
+        if let Ok(value) = sensitive_header(&format!("Bearer {}", api_key)) {
+            map.insert("Authorization", value);
+        }
         map.insert("Content-Type", "application/json".parse().unwrap());
         map
     }
@@ -53,7 +54,8 @@ impl EmbeddingRequest for OpenAiEmbeddingModel {
         Box::pin(async {
             let json = response.json().await?;
             let response: OpenAiEmbeddingResponse = serde_json::from_value(json)?;
-            Ok(response.data[0].embedding.to_owned())
+            let usage = response.usage.into();
+            Ok((response.data[0].embedding.to_owned(), Some(usage)))
         })
     }
 }
@@ -1,2 +1,10 @@
+pub mod cache;
 pub mod completions;
+pub mod credentials;
 pub mod embeddings;
+pub mod pricing;
+pub mod rate_limit;
+pub mod retry;
+pub mod secret;
+pub mod semantic_cache;
+pub mod transport;
@@ -0,0 +1,103 @@
+use super::{
+    completions::{
+        anthropic::builder::AnthropicCompletionModel, inference::CompletionRequestBuilder,
+        openai::builder::OpenAiCompletionModel, usage::TokenUsage,
+    },
+    embeddings::{inference::EmbeddingRequest, openai::OpenAiEmbeddingModel},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Price of a model in US dollars per million tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl ModelPricing {
+    pub fn new(prompt: f64, completion: f64) -> Self {
+        Self { prompt, completion }
+    }
+
+    /// Estimated cost in US dollars of the given usage
+    pub fn cost(&self, usage: TokenUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// Prices keyed by the model string sent to the provider.
+/// The default table holds list prices of the built in OpenAi, Anthropic & OpenAi embedding models,
+/// any other model can be added with `with_price`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PricingTable(HashMap<String, ModelPricing>);
+
+impl Default for PricingTable {
+    fn default() -> Self {
+        let completion_prices = [
+            (OpenAiCompletionModel::Gpt3.model_str(), 0.5, 1.5),
+            (OpenAiCompletionModel::Gpt4.model_str(), 10.0, 30.0),
+            (AnthropicCompletionModel::Opus.model_str(), 15.0, 75.0),
+            (AnthropicCompletionModel::Sonnet.model_str(), 3.0, 15.0),
+            (AnthropicCompletionModel::Haiku.model_str(), 0.25, 1.25),
+        ];
+        let embedding_prices = [
+            (OpenAiEmbeddingModel::Small.model_str(), 0.02),
+            (OpenAiEmbeddingModel::Large.model_str(), 0.13),
+            (OpenAiEmbeddingModel::Ada.model_str(), 0.1),
+        ];
+        let mut map = HashMap::new();
+        for (model, prompt, completion) in completion_prices {
+            map.insert(model.to_owned(), ModelPricing::new(prompt, completion));
+        }
+        for (model, prompt) in embedding_prices {
+            map.insert(model.to_owned(), ModelPricing::new(prompt, 0.0));
+        }
+        Self(map)
+    }
+}
+
+impl PricingTable {
+    /// A table with no prices at all
+    pub fn empty() -> Self {
+        Self(HashMap::new())
+    }
+
+    /// Add or override the price of a model
+    pub fn with_price(mut self, model: &str, pricing: ModelPricing) -> Self {
+        self.0.insert(model.to_owned(), pricing);
+        self
+    }
+
+    pub fn get(&self, model: &str) -> Option<&ModelPricing> {
+        self.0.get(model)
+    }
+
+    /// Estimated cost in US dollars, `None` if the model has no price
+    pub fn cost(&self, model: &str, usage: TokenUsage) -> Option<f64> {
+        self.get(model).map(|p| p.cost(usage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pricing_table_estimates_cost() {
+        let table = PricingTable::default();
+        let usage = TokenUsage::new(1_000_000, 500_000);
+        let haiku = AnthropicCompletionModel::Haiku.model_str();
+        assert_eq!(table.cost(haiku, usage), Some(0.25 + 0.625));
+        assert_eq!(table.cost("llama3", usage), None);
+
+        // Embeddings only use prompt tokens
+        let small = OpenAiEmbeddingModel::Small.model_str();
+        assert_eq!(table.cost(small, TokenUsage::new(500_000, 0)), Some(0.01));
+
+        let table = table.with_price("llama3", ModelPricing::new(1.0, 2.0));
+        assert_eq!(table.cost("llama3", usage), Some(2.0));
+    }
+}
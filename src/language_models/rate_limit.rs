@@ -0,0 +1,264 @@
+use super::completions::usage::TokenUsage;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::info;
+
+/// Limiters keyed by url & a hash of the api key
+type Limiters = Mutex<HashMap<(String, u64), Arc<RateLimiter>>>;
+
+/// Every model sending requests to the same url with the same api key shares one limiter
+static LIMITERS: OnceLock<Limiters> = OnceLock::new();
+
+/// Limiters unused for this long are dropped, so rotated keys & endpoints are not kept forever.
+/// By then their allowance has refilled, unless far more tokens were used than estimated
+const IDLE_LIMITER: Duration = Duration::from_secs(120);
+
+/// Client side limits on requests & tokens sent to a provider per minute.
+/// Limiters are shared by every model using the same endpoint & api key, so clones of a model and
+/// separate agents configured with the same key all draw from the same allowance. Calls over the
+/// limit wait their turn rather than failing.
+/// Tokens are estimated before sending from the size of the request plus `max_tokens`, and
+/// corrected once the provider reports the real usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn requests_per_minute(requests: u32) -> Self {
+        Self::default().with_requests_per_minute(requests)
+    }
+
+    pub fn tokens_per_minute(tokens: u32) -> Self {
+        Self::default().with_tokens_per_minute(tokens)
+    }
+
+    pub fn with_requests_per_minute(mut self, requests: u32) -> Self {
+        self.requests_per_minute = Some(requests);
+        self
+    }
+
+    pub fn with_tokens_per_minute(mut self, tokens: u32) -> Self {
+        self.tokens_per_minute = Some(tokens);
+        self
+    }
+
+    /// Waits until one request of an estimated `tokens` fits in the limits of `url` & `api_key`
+    pub(crate) async fn acquire(&self, url: &str, api_key: &str, tokens: u32) -> Permit {
+        let limiter = RateLimiter::shared(url, api_key);
+        limiter.acquire(self, tokens).await;
+        Permit { limiter, tokens }
+    }
+}
+
+/// Rough token count of a request, providers count roughly 4 characters per token
+pub(crate) fn estimate_tokens(body: &impl Serialize, max_tokens: Option<u32>) -> u32 {
+    let chars = serde_json::to_string(body)
+        .map(|s| s.len())
+        .unwrap_or_default();
+    (chars / 4) as u32 + max_tokens.unwrap_or_default()
+}
+
+/// The rate limit a request is sent under, a permit is taken before every attempt
+pub(crate) struct Throttle<'t> {
+    pub limit: &'t RateLimit,
+    pub url: &'t str,
+    pub api_key: &'t str,
+    /// Estimated tokens of one attempt
+    pub tokens: u32,
+}
+
+impl Throttle<'_> {
+    pub(crate) async fn acquire(&self) -> Permit {
+        self.limit
+            .acquire(self.url, self.api_key, self.tokens)
+            .await
+    }
+}
+
+/// Tokens taken for a request that has been let through. Dropping a permit without settling it
+/// keeps the estimate
+#[derive(Debug)]
+pub(crate) struct Permit {
+    limiter: Arc<RateLimiter>,
+    tokens: u32,
+}
+
+impl Permit {
+    /// Corrects the estimate with the usage the provider reported, if it did
+    pub(crate) fn settle(self, usage: Option<TokenUsage>) {
+        if let Some(usage) = usage {
+            let mut buckets = self.limiter.buckets.lock().unwrap();
+            if let Some(level) = buckets.tokens.as_mut() {
+                *level += self.tokens as f64 - usage.total_tokens() as f64;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// Held while waiting so callers are let through in the order they arrived
+    queue: tokio::sync::Mutex<()>,
+    buckets: Mutex<Buckets>,
+}
+
+/// Allowance left in each bucket, `None` until the first request fills it
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<f64>,
+    tokens: Option<f64>,
+    updated: Instant,
+}
+
+/// Refills `bucket` at `per_minute` and returns how long until it holds `needed`
+fn refill(
+    bucket: &mut Option<f64>,
+    per_minute: Option<u32>,
+    elapsed: Duration,
+    needed: f64,
+) -> Duration {
+    let Some(per_minute) = per_minute.map(|l| l.max(1) as f64) else {
+        return Duration::ZERO;
+    };
+    let level = bucket.get_or_insert(per_minute);
+    *level = (*level + elapsed.as_secs_f64() * per_minute / 60.0).min(per_minute);
+    let needed = needed.min(per_minute);
+    match *level >= needed {
+        true => Duration::ZERO,
+        false => Duration::from_secs_f64((needed - *level) * 60.0 / per_minute),
+    }
+}
+
+impl RateLimiter {
+    fn shared(url: &str, api_key: &str) -> Arc<Self> {
+        let mut hasher = DefaultHasher::new();
+        api_key.hash(&mut hasher);
+        let key = (url.to_owned(), hasher.finish());
+        let mut limiters = LIMITERS.get_or_init(Default::default).lock().unwrap();
+        // Limiters only referenced by the map have no request in flight
+        limiters.retain(|_, limiter| {
+            Arc::strong_count(limiter) > 1
+                || limiter.buckets.lock().unwrap().updated.elapsed() < IDLE_LIMITER
+        });
+        limiters
+            .entry(key)
+            .or_insert_with(|| {
+                Arc::new(Self {
+                    queue: tokio::sync::Mutex::new(()),
+                    buckets: Mutex::new(Buckets {
+                        requests: None,
+                        tokens: None,
+                        updated: Instant::now(),
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    async fn acquire(&self, limit: &RateLimit, tokens: u32) {
+        let _turn = self.queue.lock().await;
+        loop {
+            let wait = self.try_take(limit, tokens);
+            if wait.is_zero() {
+                return;
+            }
+            info!("Rate limited, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes one request & `tokens` if both fit, otherwise returns how long until they will
+    fn try_take(&self, limit: &RateLimit, tokens: u32) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now - buckets.updated;
+        buckets.updated = now;
+        let wait = refill(
+            &mut buckets.requests,
+            limit.requests_per_minute,
+            elapsed,
+            1.0,
+        )
+        .max(refill(
+            &mut buckets.tokens,
+            limit.tokens_per_minute,
+            elapsed,
+            tokens as f64,
+        ));
+        if wait.is_zero() {
+            if let Some(requests) = buckets.requests.as_mut() {
+                *requests -= 1.0;
+            }
+            if let Some(level) = buckets.tokens.as_mut() {
+                *level -= tokens as f64;
+            }
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_over_the_limit_wait() {
+        let limit = RateLimit::requests_per_minute(2).with_tokens_per_minute(1000);
+        let url = "http://limited.test/requests";
+        let limiter = RateLimiter::shared(url, "key");
+        assert!(Arc::ptr_eq(&limiter, &RateLimiter::shared(url, "key")));
+        assert!(!Arc::ptr_eq(
+            &limiter,
+            &RateLimiter::shared(url, "other key")
+        ));
+
+        assert!(limiter.try_take(&limit, 10).is_zero());
+        assert!(limiter.try_take(&limit, 10).is_zero());
+        let wait = limiter.try_take(&limit, 10);
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn tokens_are_metered_and_settled() {
+        let limit = RateLimit::tokens_per_minute(600);
+        let limiter = RateLimiter::shared("http://limited.test/tokens", "key");
+        assert!(limiter.try_take(&limit, 600).is_zero());
+        // Only 60 of the estimated 600 tokens were used
+        Permit {
+            limiter: limiter.clone(),
+            tokens: 600,
+        }
+        .settle(Some(TokenUsage::new(50, 10)));
+        assert!(limiter.try_take(&limit, 540).is_zero());
+
+        let wait = limiter.try_take(&limit, 300);
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn idle_limiters_are_evicted() {
+        let idle = RateLimiter::shared("http://limited.test/idle", "key");
+        let held = RateLimiter::shared("http://limited.test/held", "key");
+        for limiter in [&idle, &held] {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            buckets.updated = Instant::now().checked_sub(IDLE_LIMITER).unwrap();
+        }
+        let evicted = Arc::downgrade(&idle);
+        drop(idle);
+
+        RateLimiter::shared("http://limited.test/other", "key");
+        assert!(evicted.upgrade().is_none());
+        assert!(Arc::ptr_eq(
+            &held,
+            &RateLimiter::shared("http://limited.test/held", "key")
+        ));
+    }
+}
@@ -0,0 +1,314 @@
+use super::{
+    completions::{
+        error::{CompletionError, ProviderError},
+        usage::TokenUsage,
+    },
+    embeddings::error::EmbeddingError,
+    rate_limit::{Permit, Throttle},
+};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Anthropic reports a reset time for each of these limits
+const ANTHROPIC_RATELIMITS: [&str; 4] = ["requests", "tokens", "input-tokens", "output-tokens"];
+
+/// How requests to a provider are retried.
+/// Network errors and error responses `CompletionError::is_retryable` accepts are retried, such as
+/// 408, 409, rate limits other than running out of quota & 5xx (including Anthropic's 529
+/// overloaded). When the provider says how long to wait through `retry-after`, `retry-after-ms` or
+/// an exhausted `anthropic-ratelimit-*` limit, that wait is used instead of the backoff
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one, 1 disables retrying
+    pub max_attempts: u32,
+    /// Wait before the first retry
+    pub initial_backoff: Duration,
+    /// Longest wait between attempts, waits asked for by the provider included
+    pub max_backoff: Duration,
+    /// Each retry waits this many times longer than the last
+    pub multiplier: f64,
+    /// Randomize each backoff between half and all of its computed value
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Make every request exactly once
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Negative & non finite multipliers are replaced by 1, keeping the backoff constant
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = match multiplier.is_finite() && multiplier >= 0.0 {
+            true => multiplier,
+            false => 1.0,
+        };
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Backoff before retry number `retry`, starting at 1
+    fn backoff(&self, retry: u32) -> Duration {
+        let exp = self
+            .multiplier
+            .powi(i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX));
+        // Computed in seconds so huge exponents & deserialized nonsense multipliers cannot
+        // overflow, a NaN wait becomes `max_backoff`
+        let secs = (self.initial_backoff.as_secs_f64() * exp)
+            .min(self.max_backoff.as_secs_f64())
+            .max(0.0);
+        let backoff = Duration::try_from_secs_f64(secs).unwrap_or(self.max_backoff);
+        match self.jitter {
+            true => backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0)),
+            false => backoff,
+        }
+    }
+
+    /// Sends the request built by `request` until it succeeds, fails with an error that is not
+    /// retryable or attempts run out. Error responses are returned as `SendError::Provider`.
+    /// Every attempt waits for a permit of `throttle`, failed attempts give their tokens back &
+    /// the permit of the successful one is returned for the caller to settle
+    pub(crate) async fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        throttle: Option<&Throttle<'_>>,
+    ) -> Result<(Response, Option<Permit>), SendError> {
+        let mut attempt = 1;
+        loop {
+            let permit = match throttle {
+                Some(throttle) => Some(throttle.acquire().await),
+                None => None,
+            };
+            let (err, requested_wait) = match request().send().await {
+                Ok(response) => {
+                    let requested_wait = retry_after(response.headers());
+                    match ProviderError::check_response(response).await {
+                        Ok(response) => return Ok((response, permit)),
+                        Err(err) => (SendError::Provider(err), requested_wait),
+                    }
+                }
+                Err(err) => (SendError::Request(err), None),
+            };
+            if let Some(permit) = permit {
+                permit.settle(Some(TokenUsage::default()));
+            }
+            if attempt >= self.max_attempts || !err.is_retryable() {
+                return Err(err);
+            }
+            warn!(
+                "Attempt {} of {} failed: {:?}",
+                attempt, self.max_attempts, err
+            );
+            let wait = match requested_wait {
+                Some(wait) => wait.min(self.max_backoff),
+                None => self.backoff(attempt),
+            };
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Why `RetryPolicy::send` gave up
+#[derive(Debug)]
+pub(crate) enum SendError {
+    Request(reqwest::Error),
+    /// The provider responded with an error status
+    Provider(ProviderError),
+}
+
+impl SendError {
+    /// Error responses are classified like `CompletionError`s, so running out of quota is not
+    /// retried
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(err) => is_retryable_request(err),
+            Self::Provider(err) => CompletionError::from(err.clone()).is_retryable(),
+        }
+    }
+}
+
+impl From<SendError> for CompletionError {
+    fn from(err: SendError) -> Self {
+        match err {
+            SendError::Request(err) => err.into(),
+            SendError::Provider(err) => err.into(),
+        }
+    }
+}
+
+impl From<SendError> for EmbeddingError {
+    fn from(err: SendError) -> Self {
+        match err {
+            SendError::Request(err) => err.into(),
+            SendError::Provider(err) => err.into(),
+        }
+    }
+}
+
+pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 409 | 429) || status.is_server_error()
+}
+
+/// Connecting failed, the request timed out or it got a retryable status. Errors building the
+/// request, such as a bad url, fail the same way every time
+pub(crate) fn is_retryable_request(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.status().is_some_and(is_retryable_status)
+}
+
+/// The longest wait asked for by the provider's response headers. Waits too long to represent,
+/// such as `inf`, are ignored
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok();
+    let now = SystemTime::now();
+    let until = |time: DateTime<Utc>| {
+        SystemTime::from(time)
+            .duration_since(now)
+            .unwrap_or_default()
+    };
+
+    let secs = |secs: f64| Duration::try_from_secs_f64(secs.max(0.0)).ok();
+
+    let mut waits = vec![];
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        waits.extend(secs(ms / 1000.0));
+    }
+    if let Some(value) = header("retry-after") {
+        match value.parse::<f64>() {
+            Ok(value) => waits.extend(secs(value)),
+            Err(_) => {
+                if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+                    waits.push(until(date.with_timezone(&Utc)));
+                }
+            }
+        }
+    }
+    for limit in ANTHROPIC_RATELIMITS {
+        if header(&format!("anthropic-ratelimit-{}-remaining", limit)) != Some("0") {
+            continue;
+        }
+        if let Some(reset) = header(&format!("anthropic-ratelimit-{}-reset", limit))
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+        {
+            waits.push(until(reset.with_timezone(&Utc)));
+        }
+    }
+    waits.into_iter().max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(350))
+            .with_jitter(false);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(350));
+
+        let jittered = policy.clone().with_jitter(true).backoff(2);
+        assert!(jittered >= Duration::from_millis(100) && jittered <= Duration::from_millis(200));
+
+        assert_eq!(policy.backoff(70), Duration::from_millis(350));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(350));
+        for multiplier in [-2.0, f64::NAN, f64::INFINITY] {
+            let constant = policy.clone().with_multiplier(multiplier);
+            assert_eq!(constant.multiplier, 1.0);
+            assert_eq!(constant.backoff(5), Duration::from_millis(100));
+        }
+        let deserialized = RetryPolicy {
+            multiplier: -2.0,
+            ..policy.clone()
+        };
+        assert_eq!(deserialized.backoff(2), Duration::ZERO);
+        assert_eq!(deserialized.backoff(3), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn retry_after_headers_read() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert("retry-after", "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+
+        headers.insert("retry-after-ms", "2500".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(2500)));
+
+        let reset = DateTime::<Utc>::from(SystemTime::now() + Duration::from_secs(60));
+        headers.insert(
+            "anthropic-ratelimit-tokens-reset",
+            reset.to_rfc3339().parse().unwrap(),
+        );
+        headers.insert(
+            "anthropic-ratelimit-tokens-remaining",
+            "10".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(2500)));
+
+        headers.insert("anthropic-ratelimit-tokens-remaining", "0".parse().unwrap());
+        let wait = retry_after(&headers).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn invalid_requests_are_not_retried() {
+        let err = reqwest::Client::new().get("not a url").build().unwrap_err();
+        assert!(err.is_builder());
+        assert!(!is_retryable_request(&err));
+        assert!(!SendError::Request(err).is_retryable());
+    }
+
+    #[test]
+    fn unrepresentable_retry_after_is_ignored() {
+        let cases = [
+            ("inf", None),
+            ("1e30", None),
+            ("NaN", Some(Duration::ZERO)),
+            ("-5", Some(Duration::ZERO)),
+        ];
+        for (value, expected) in cases {
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after", value.parse().unwrap());
+            headers.insert("retry-after-ms", value.parse().unwrap());
+            assert_eq!(retry_after(&headers), expected, "retry-after {:?}", value);
+        }
+    }
+}
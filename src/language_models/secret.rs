@@ -0,0 +1,108 @@
+use reqwest::header::{HeaderMap, HeaderValue, InvalidHeaderValue};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Headers whose values are never logged
+const SENSITIVE_HEADERS: [&str; 6] = [
+    "authorization",
+    "proxy-authorization",
+    "x-api-key",
+    "api-key",
+    "cookie",
+    "openai-organization",
+];
+
+/// A secret such as an api key. It is never printed in clear & is not serialized, so models
+/// holding one must be given it again after being deserialized
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Self(secret.into())
+    }
+
+    /// The secret in clear, only for sending it to where it belongs
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "SecretString({})", REDACTED)
+    }
+}
+
+impl Display for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+
+/// Header value marked sensitive, so `reqwest` & `http` never print it. Errors if `value` holds
+/// characters a header cannot, keys are checked by `Credentials::resolve` before any headers are
+/// built so providers leave the header out instead
+pub(crate) fn sensitive_header(value: &str) -> Result<HeaderValue, InvalidHeaderValue> {
+    let mut value = HeaderValue::from_str(value)?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// Prints headers with the values of credential headers, or any marked sensitive, redacted
+pub(crate) struct RedactedHeaders<'h>(pub &'h HeaderMap);
+
+impl Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| {
+                let redact = value.is_sensitive() || SENSITIVE_HEADERS.contains(&name.as_str());
+                let value = match (redact, value.to_str()) {
+                    (true, _) => REDACTED,
+                    (false, Ok(value)) => value,
+                    (false, Err(_)) => "<binary>",
+                };
+                (name.as_str(), value)
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_never_printed() {
+        let key = SecretString::from("sk-live-123");
+        assert_eq!(key.expose(), "sk-live-123");
+        assert!(!format!("{:?} {}", key, key).contains("sk-live-123"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer sk-live-123".parse().unwrap());
+        headers.insert("x-custom-auth", sensitive_header("sk-live-123").unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        let printed = format!("{:?}", RedactedHeaders(&headers));
+        assert!(!printed.contains("sk-live-123"));
+        assert!(printed.contains("application/json"));
+        assert!(!format!("{:?}", headers.get("x-custom-auth")).contains("sk-live-123"));
+        assert!(sensitive_header("sk-live-123\n").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use super::{
+    completions::{inference::IoCompletion, usage::TokenUsage},
+    embeddings::{error::EmbeddingResult, EmbeddingModel},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+/// Hits & misses of a `SemanticCache` since it was created or its stats were last reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of lookups that hit, 0 before any lookup
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SemanticEntry {
+    /// Hash of the model & system prompt that produced the completion
+    scope: u64,
+    embedding: Vec<f32>,
+    completion: IoCompletion,
+}
+
+#[derive(Debug)]
+struct SemanticCacheState {
+    entries: VecDeque<SemanticEntry>,
+    stats: CacheStats,
+}
+
+/// Answers an io completion with a stored one when the last user message is similar enough to
+/// the one that produced it. Messages are compared by the cosine similarity of their embeddings,
+/// only completions of the same model & system prompt are considered & the rest of the
+/// conversation is not. Clones share the same entries & stats
+#[derive(Debug, Clone)]
+pub struct SemanticCache {
+    embedder: EmbeddingModel,
+    /// Cosine similarity at or above which a stored completion is returned
+    threshold: f32,
+    /// Most entries kept, the oldest is dropped once full
+    capacity: usize,
+    state: Arc<Mutex<SemanticCacheState>>,
+}
+
+impl SemanticCache {
+    /// Threshold defaults to 0.95 & capacity to 1000 entries
+    pub fn new(embedder: EmbeddingModel) -> Self {
+        Self {
+            embedder,
+            threshold: 0.95,
+            capacity: 1000,
+            state: Arc::new(Mutex::new(SemanticCacheState {
+                entries: VecDeque::new(),
+                stats: CacheStats::default(),
+            })),
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    pub fn embedder(&self) -> &EmbeddingModel {
+        &self.embedder
+    }
+
+    /// Sets the client of the embedding model, the entries shared with clones are untouched
+    pub fn set_client(&mut self, client: reqwest::Client) {
+        self.embedder.set_client(client);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.state.lock().unwrap().stats = CacheStats::default();
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().entries.clear();
+    }
+
+    /// The embedding of `message` & the tokens the embedder reported
+    pub(crate) async fn embed(
+        &self,
+        message: &str,
+    ) -> EmbeddingResult<(Vec<f32>, Option<TokenUsage>)> {
+        self.embedder.embed(message, true).await
+    }
+
+    /// Scope of completions made by `model` with `system_prompt`
+    pub(crate) fn scope(model: &str, system_prompt: Option<&str>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (model, system_prompt).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The stored completion in `scope` most similar to `embedding`, if any reaches the threshold.
+    /// Counts a hit or a miss
+    pub(crate) fn lookup(&self, scope: u64, embedding: &[f32]) -> Option<IoCompletion> {
+        let mut state = self.state.lock().unwrap();
+        let best = state
+            .entries
+            .iter()
+            .filter(|entry| entry.scope == scope)
+            .map(|entry| (cosine_similarity(embedding, &entry.embedding), entry))
+            .filter(|(similarity, _)| *similarity >= self.threshold)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, entry)| entry.completion.clone());
+        match best {
+            Some(_) => state.stats.hits += 1,
+            None => state.stats.misses += 1,
+        }
+        best
+    }
+
+    pub(crate) fn insert(&self, scope: u64, embedding: Vec<f32>, completion: IoCompletion) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.push_back(SemanticEntry {
+            scope,
+            embedding,
+            completion,
+        });
+        while state.entries.len() > self.capacity {
+            state.entries.pop_front();
+        }
+    }
+}
+
+/// 0 if either vector has no magnitude or their lengths differ
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let magnitude = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let magnitudes = magnitude(a) * magnitude(b);
+    if magnitudes == 0.0 {
+        return 0.0;
+    }
+    dot / magnitudes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::inference::CompletionChoice;
+
+    #[test]
+    fn similar_embeddings_hit() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < f32::EPSILON);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+
+        let cache = SemanticCache::new(EmbeddingModel::default_ollama("nomic-embed-text"))
+            .with_threshold(0.9)
+            .with_capacity(1);
+        let completion =
+            IoCompletion::new(vec![CompletionChoice::new("Paris".to_owned(), None)], None);
+        let scope = SemanticCache::scope("gpt-4", Some("system"));
+        cache.insert(scope, vec![1.0, 0.0], completion.clone());
+
+        assert_eq!(cache.lookup(scope, &[0.99, 0.05]), Some(completion));
+        assert_eq!(cache.lookup(scope, &[0.5, 0.5]), None);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+        assert_eq!(cache.stats().hit_rate(), 0.5);
+
+        // Completions of another model or system prompt are never reused
+        for (model, system_prompt) in [("gpt-4", Some("pirate")), ("gpt-3.5-turbo", Some("system"))]
+        {
+            let other = SemanticCache::scope(model, system_prompt);
+            assert_eq!(cache.lookup(other, &[1.0, 0.0]), None);
+        }
+
+        cache.insert(scope, vec![0.0, 1.0], IoCompletion::new(vec![], None));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.lookup(scope, &[1.0, 0.0]), None);
+    }
+}
@@ -0,0 +1,82 @@
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Certificate, Client, Proxy,
+};
+use std::{
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+/// Client used by every model not given one of its own
+static DEFAULT_CLIENT: OnceLock<RwLock<Client>> = OnceLock::new();
+
+/// The client models use unless given one with `with_client`. Models built or deserialized
+/// before it is replaced with `set_default_client` keep the client they were given
+pub fn default_client() -> Client {
+    DEFAULT_CLIENT
+        .get_or_init(|| RwLock::new(Client::new()))
+        .read()
+        .unwrap()
+        .clone()
+}
+
+/// Replaces the client every model built or deserialized from now on is given, for example one
+/// built from a `TransportConfig` before deserializing saved agents
+pub fn set_default_client(client: Client) {
+    let lock = DEFAULT_CLIENT.get_or_init(|| RwLock::new(client.clone()));
+    *lock.write().unwrap() = client;
+}
+
+/// Settings for building the HTTP client models send requests with. Clones of a `reqwest::Client`
+/// share one connection pool, so build it once & give it to every model.
+/// There is no overall request timeout, as `reqwest` would cut off any stream running longer than
+/// it. Use `CompletionModel::with_timeout` to limit how long a model may take to answer
+#[derive(Debug, Clone, Default)]
+pub struct TransportConfig {
+    /// Proxy every request is sent through, such as `http://proxy.corp:8080`
+    pub proxy: Option<String>,
+    /// PEM encoded certificates trusted in addition to the system's
+    pub root_certificates: Vec<Vec<u8>>,
+    pub connect_timeout: Option<Duration>,
+    /// Sent with every request, such as `OpenAI-Organization`
+    pub headers: HeaderMap,
+}
+
+impl TransportConfig {
+    pub fn with_proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_owned());
+        self
+    }
+
+    pub fn with_root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Values are marked sensitive, so they are never logged
+    pub fn with_header(mut self, name: HeaderName, mut value: HeaderValue) -> Self {
+        value.set_sensitive(true);
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Fails if the proxy url or a certificate is invalid
+    pub fn build(&self) -> reqwest::Result<Client> {
+        let mut builder = Client::builder().default_headers(self.headers.clone());
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        builder.build()
+    }
+}
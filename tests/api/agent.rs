@@ -1,11 +1,23 @@
-use crate::{init_test, test_anthropic_agent, test_openai_agent};
+use crate::{init_test, test_anthropic_agent, test_openai_agent, StubResponse, StubServer};
 use espionox::{
-    agents::memory::Message,
-    language_models::completions::{functions::Function, streaming::ProviderStreamHandler},
+    agents::{budget::Budget, memory::Message, Agent, AgentError},
+    language_models::{
+        completions::{
+            functions::{Function, ToolChoice},
+            inference::{CompletionChoice, FinishReason},
+            ollama::builder::OllamaCompletionModel,
+            openai::compatible::OpenAiCompatibleModel,
+            streaming::ProviderStreamHandler,
+            usage::TokenUsage,
+            CompletionModel, ModelParameters,
+        },
+        pricing::{ModelPricing, PricingTable},
+    },
 };
 use serde::Deserialize;
 use serde_json::json;
 use serde_json::Value;
+use std::time::Duration;
 use tracing::info;
 
 #[ignore]
@@ -78,3 +90,259 @@ async fn function_prompt_agent_works() {
         }
     }
 }
+
+#[tokio::test]
+async fn tool_completion_returns_every_call() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "id": "chatcmpl-abc123",
+            "object": "chat.completion",
+            "created": 1699896916,
+            "model": "local-model",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {
+                            "id": "call_1",
+                            "type": "function",
+                            "function": {"name": "get_weather", "arguments": "{\"location\": \"Detroit\"}"}
+                        },
+                        {
+                            "id": "call_2",
+                            "type": "function",
+                            "function": {"name": "get_weather", "arguments": "{\"location\": \"Chicago\"}"}
+                        }
+                    ]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 82, "completion_tokens": 17, "total_tokens": 99}
+        }),
+    )])
+    .await;
+    let model = OpenAiCompatibleModel::new(&server.url, "local-model");
+    let mut a = Agent::new(
+        None,
+        CompletionModel::new(model, ModelParameters::default(), ""),
+    );
+    a.cache
+        .push(Message::new_user("Weather in Detroit and Chicago?"));
+    let functions = vec![
+        Function::try_from("get_weather(location!: string)").unwrap(),
+        Function::try_from("get_time(timezone!: string)").unwrap(),
+    ];
+
+    let calls = a
+        .tool_completion(functions, ToolChoice::Required)
+        .await
+        .unwrap();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].id, "call_2");
+    assert_eq!(calls[1].arguments, json!({"location": "Chicago"}));
+    assert_eq!(a.usage().total(), TokenUsage::new(82, 17));
+
+    let req = server.requests()[0].json();
+    assert_eq!(req["tool_choice"], "required");
+    assert_eq!(req["tools"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn budget_stops_completions_once_spent() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "id": "chatcmpl-local",
+            "object": "chat.completion",
+            "created": 1677858242,
+            "model": "mistral-7b",
+            "usage": {"prompt_tokens": 13, "completion_tokens": 7, "total_tokens": 20},
+            "choices": [{
+                "message": {"role": "assistant", "content": "hello"},
+                "finish_reason": "stop",
+                "index": 0
+            }]
+        }),
+    )])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "");
+
+    let mut a = Agent::new(Some("system"), llm.clone()).with_budget(Budget::tokens(40));
+    a.io_completion().await.unwrap();
+    a.io_completion().await.unwrap();
+    let err = a.io_completion().await.unwrap_err();
+    assert!(matches!(
+        err,
+        AgentError::TokenBudgetExceeded {
+            used: 40,
+            limit: 40
+        }
+    ));
+    assert_eq!(server.requests().len(), 2);
+
+    let pricing = PricingTable::default().with_price("mistral-7b", ModelPricing::new(1.0, 1.0));
+    let mut a =
+        Agent::new(Some("system"), llm).with_budget(Budget::dollars(0.00003).with_pricing(pricing));
+    a.io_completion().await.unwrap();
+    assert_eq!(a.usage().cost(), 0.00002);
+    a.io_completion().await.unwrap();
+    let err = a.stream_completion().await.unwrap_err();
+    assert!(matches!(err, AgentError::CostBudgetExceeded { .. }));
+    assert_eq!(server.requests().len(), 4);
+}
+
+#[tokio::test]
+async fn multi_choice_completion_returns_every_choice() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "id": "chatcmpl-local",
+            "object": "chat.completion",
+            "created": 1677858242,
+            "model": "mistral-7b",
+            "usage": {"prompt_tokens": 13, "completion_tokens": 14, "total_tokens": 27},
+            "choices": [
+                {
+                    "message": {"role": "assistant", "content": "first"},
+                    "finish_reason": "stop",
+                    "index": 0
+                },
+                {
+                    "message": {"role": "assistant", "content": "second, cut"},
+                    "finish_reason": "length",
+                    "index": 1
+                }
+            ]
+        }),
+    )])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "");
+    let mut a = Agent::new(Some("system"), llm);
+
+    let choices = a.multi_choice_completion(2).await.unwrap();
+    assert_eq!(
+        choices,
+        vec![
+            CompletionChoice::new("first".to_owned(), Some("stop")),
+            CompletionChoice::new("second, cut".to_owned(), Some("length")),
+        ]
+    );
+    assert_eq!(choices[1].finish_reason, Some(FinishReason::Length));
+    assert_eq!(server.requests().len(), 1);
+    assert_eq!(server.requests()[0].json()["n"], 2);
+    assert_eq!(a.usage().total(), TokenUsage::new(13, 14));
+}
+
+#[tokio::test]
+async fn multi_choice_completion_emulated_with_concurrent_requests() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "model": "llama3",
+            "created_at": "2023-12-12T14:13:43.416799Z",
+            "message": {"role": "assistant", "content": "candidate"},
+            "done": true,
+            "done_reason": "stop",
+            "prompt_eval_count": 10,
+            "eval_count": 2
+        }),
+    )])
+    .await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::with_host(&server.url, "llama3"),
+        ModelParameters::default(),
+        "",
+    );
+    let mut a = Agent::new(Some("system"), llm);
+
+    let choices = a.multi_choice_completion(3).await.unwrap();
+    assert_eq!(choices.len(), 3);
+    assert!(choices
+        .iter()
+        .all(|c| c.finish_reason == Some(FinishReason::Stop)));
+    assert_eq!(server.requests().len(), 3);
+    assert_eq!(a.usage().total(), TokenUsage::new(30, 6));
+    assert_eq!(a.usage().completions(), 1);
+}
+
+#[tokio::test]
+async fn detailed_io_completion_carries_metadata() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "id": "chatcmpl-local",
+            "object": "chat.completion",
+            "created": 1677858242,
+            "model": "mistral-7b-instruct-v0.2.Q4_K_M",
+            "usage": {"prompt_tokens": 13, "completion_tokens": 10, "total_tokens": 23},
+            "choices": [{
+                "message": {"role": "assistant", "content": "This answer was cut"},
+                "finish_reason": "length",
+                "index": 0
+            }]
+        }),
+    )
+    .with_header("x-request-id", "req_123")])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "");
+    let mut a = Agent::new(Some("system"), llm);
+
+    let completion = a.detailed_io_completion().await.unwrap();
+    assert_eq!(completion.content(), Some("This answer was cut"));
+    assert_eq!(completion.finish_reason(), Some(&FinishReason::Length));
+    assert_eq!(
+        completion.model.as_deref(),
+        Some("mistral-7b-instruct-v0.2.Q4_K_M")
+    );
+    assert_eq!(completion.request_id.as_deref(), Some("req_123"));
+    assert_eq!(completion.usage, Some(TokenUsage::new(13, 10)));
+    assert!(completion.latency > Duration::ZERO);
+    assert_eq!(a.usage().total(), TokenUsage::new(13, 10));
+}
+
+#[tokio::test]
+async fn filtered_choices_keep_their_finish_reason() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "model": "gpt-4o-mini",
+            "usage": {"prompt_tokens": 12, "completion_tokens": 0, "total_tokens": 12},
+            "choices": [
+                {
+                    "message": {"role": "assistant", "content": null},
+                    "finish_reason": "content_filter",
+                    "index": 0
+                },
+                {
+                    "message": {"role": "assistant", "content": "Fine"},
+                    "finish_reason": "stop",
+                    "index": 1
+                }
+            ]
+        }),
+    )])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "gpt-4o-mini");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "");
+    let mut a = Agent::new(Some("system"), llm);
+
+    let completion = a.detailed_io_completion().await.unwrap();
+    assert_eq!(completion.content(), Some(""));
+    assert_eq!(
+        completion.finish_reason(),
+        Some(&FinishReason::ContentFilter)
+    );
+    assert_eq!(completion.choices[1].content, "Fine");
+}
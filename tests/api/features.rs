@@ -17,7 +17,6 @@ async fn vision_completion_works() {
     dotenv::dotenv().ok();
     let client = reqwest::Client::new();
     let api_key = std::env::var("OPENAI_KEY").unwrap();
-    tracing::info!("API KEY: {}", api_key);
     let image_url = "./tests/test-screenshot.png";
     let mut messages = MessageStack::new("You are an image looker-atter");
     messages.push(Message::new_user("What is this image?"));
@@ -1,7 +1,10 @@
 use dotenv::dotenv;
 use espionox::{
     agents::Agent,
-    language_models::completions::CompletionModel,
+    language_models::completions::{
+        anthropic::builder::AnthropicCompletionModel, openai::builder::OpenAiCompletionModel,
+        CompletionModel, ModelParameters,
+    },
     telemetry::{get_subscriber, init_subscriber},
 };
 use once_cell::sync::Lazy;
@@ -25,16 +28,18 @@ pub fn init_test() {
 
 pub fn test_openai_agent() -> Agent {
     dotenv().ok();
-    let api_key = std::env::var("OPENAI_KEY").unwrap();
-    let llm = CompletionModel::default_openai(&api_key);
+    let llm =
+        CompletionModel::from_env(OpenAiCompletionModel::default(), ModelParameters::default());
 
     Agent::new(Some("I am running tests, say hello"), llm)
 }
 
 pub fn test_anthropic_agent() -> Agent {
     dotenv().ok();
-    let api_key = std::env::var("ANTHROPIC_KEY").unwrap();
-    let llm = CompletionModel::default_anthropic(&api_key);
+    let llm = CompletionModel::from_env(
+        AnthropicCompletionModel::default(),
+        ModelParameters::default(),
+    );
 
     Agent::new(Some("I am running tests, say hello"), llm)
 }
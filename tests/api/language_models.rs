@@ -1,5 +1,38 @@
-use crate::init_test;
-use espionox::{agents::Agent, language_models::completions::CompletionModel};
+use crate::{init_test, StubResponse, StubServer};
+use espionox::{
+    agents::{memory::Message, Agent},
+    language_models::{
+        cache::ResponseCache,
+        completions::{
+            error::{CompletionError, CompletionResult},
+            fallback::FallbackMode,
+            functions::{Function, ToolCall},
+            inference::{
+                CompletionRequest, CompletionRequestBuilder, CompletionResponse,
+                ProcessResponseReturn,
+            },
+            ollama::builder::OllamaCompletionModel,
+            openai::compatible::{CompatibleAuth, OpenAiCompatibleModel},
+            streaming::{
+                partial_json::PartialJsonStream, CompletionStreamStatus, ProviderStreamHandler,
+                StreamEvent,
+            },
+            usage::TokenUsage,
+            CompletionModel, CompletionProvider, ModelParameters,
+        },
+        credentials::{CredentialError, EnvVarKey, KeyCallback, RoundRobinKeys},
+        embeddings::{ollama::OllamaEmbeddingModel, EmbeddingModel},
+        rate_limit::RateLimit,
+        retry::RetryPolicy,
+        semantic_cache::{CacheStats, SemanticCache},
+        transport::TransportConfig,
+    },
+    prelude::MessageStack,
+};
+use futures::TryStreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use std::time::Duration;
 
 #[tokio::test]
 async fn failed_request_does_not_overflow_stack() {
@@ -12,3 +45,960 @@ async fn failed_request_does_not_overflow_stack() {
     println!("{:?}", res);
     assert!(res.is_err());
 }
+
+#[derive(Debug)]
+struct EchoProvider {
+    url: String,
+}
+
+#[derive(Debug)]
+struct EchoRequest {
+    model: String,
+    messages: Value,
+}
+
+impl CompletionRequestBuilder for EchoProvider {
+    fn model_str(&self) -> &str {
+        "echo-1"
+    }
+    fn url_str(&self) -> &str {
+        &self.url
+    }
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        stack
+            .as_ref()
+            .iter()
+            .map(|m| json!({"from": m.role.to_string(), "text": m.content}))
+            .collect::<Vec<Value>>()
+            .into()
+    }
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("echo-key", api_key.parse().unwrap());
+        map
+    }
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        _params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(EchoRequest {
+            model: self.model_str().to_owned(),
+            messages: self.serialize_messages(stack),
+        }))
+    }
+}
+
+impl CompletionRequest for EchoRequest {
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(json!({"model": self.model, "messages": self.messages}))
+    }
+    fn process_response(&self, response: reqwest::Response) -> ProcessResponseReturn<'_> {
+        Box::pin(async move {
+            let json: Value = response.json().await?;
+            let reply = json["reply"].as_str().unwrap_or_default().to_owned();
+            Ok(CompletionResponse::from(reply))
+        })
+    }
+}
+
+#[tokio::test]
+async fn custom_provider_works_with_agent() {
+    init_test();
+    let server =
+        StubServer::start(vec![StubResponse::json(200, json!({"reply": "hi there"}))]).await;
+    let provider = CompletionProvider::custom(EchoProvider {
+        url: format!("{}/echo", server.url),
+    });
+    let llm = CompletionModel::new(provider, ModelParameters::default(), "echo_key");
+    let mut a = Agent::new(Some("system"), llm);
+    a.cache.push(Message::new_user("hello"));
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res, "hi there");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/echo");
+    assert_eq!(requests[0].headers["echo-key"], "echo_key");
+    assert_eq!(requests[0].json()["messages"][1]["text"], "hello");
+}
+
+#[tokio::test]
+async fn openai_compatible_provider_hits_configured_server() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "id": "chatcmpl-local",
+            "object": "chat.completion",
+            "created": 1677858242,
+            "model": "mistral-7b",
+            "usage": {"prompt_tokens": 13, "completion_tokens": 7, "total_tokens": 20},
+            "choices": [{
+                "message": {"role": "assistant", "content": "hello from vllm"},
+                "finish_reason": "stop",
+                "index": 0
+            }]
+        }),
+    )])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b")
+        .with_auth(CompatibleAuth::Header("api-key".to_owned()))
+        .unwrap();
+    let llm = CompletionModel::new(model, ModelParameters::default(), "local_key");
+    let mut a = Agent::new(Some("system"), llm);
+    a.cache.push(Message::new_user("hello"));
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res, "hello from vllm");
+    assert_eq!(a.usage().last(), Some(TokenUsage::new(13, 7)));
+
+    a.io_completion().await.unwrap();
+    assert_eq!(a.usage().total(), TokenUsage::new(26, 14));
+    assert_eq!(a.usage().completions(), 2);
+
+    let requests = server.requests();
+    assert_eq!(requests[0].path, "/v1/chat/completions");
+    assert_eq!(requests[0].headers["api-key"], "local_key");
+    assert!(!requests[0].headers.contains_key("authorization"));
+    assert_eq!(requests[0].json()["model"], "mistral-7b");
+}
+
+#[tokio::test]
+async fn ollama_io_completion_works() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({
+            "model": "llama3",
+            "created_at": "2023-12-12T14:13:43.416799Z",
+            "message": {"role": "assistant", "content": "Hello from llama"},
+            "done": true,
+            "prompt_eval_count": 26,
+            "eval_count": 5
+        }),
+    )])
+    .await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::with_host(&server.url, "llama3"),
+        ModelParameters::default(),
+        "",
+    );
+    let mut a = Agent::new(Some("system"), llm);
+    a.cache.push(Message::new_user("hello"));
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res, "Hello from llama");
+
+    let req = &server.requests()[0];
+    assert_eq!(req.path, "/api/chat");
+    assert_eq!(req.json()["model"], "llama3");
+    assert_eq!(req.json()["stream"], false);
+    assert_eq!(req.json()["messages"][1]["content"], "hello");
+}
+
+#[tokio::test]
+async fn ollama_stream_completion_works() {
+    init_test();
+    let chunk = |content: &str, done: bool| {
+        json!({
+            "model": "llama3",
+            "created_at": "2023-08-04T08:52:19.385406455-07:00",
+            "message": {"role": "assistant", "content": content},
+            "done": done
+        })
+    };
+    let mut last = chunk("", true);
+    last["prompt_eval_count"] = json!(26);
+    last["eval_count"] = json!(3);
+    let server = StubServer::start(vec![StubResponse::ndjson(vec![
+        chunk("Hel", false),
+        chunk("lo", false),
+        chunk("!", false),
+        last,
+    ])])
+    .await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::with_host(&server.url, "llama3"),
+        ModelParameters::default(),
+        "",
+    );
+    let mut a = Agent::new(Some("system"), llm);
+    a.cache.push(Message::new_user("hello"));
+
+    let mut response: ProviderStreamHandler = a.stream_completion().await.unwrap();
+    let mut tokens = vec![];
+    while let Ok(Some(status)) = response.receive(&mut a).await {
+        match status {
+            CompletionStreamStatus::Working(t) => tokens.push(t),
+            CompletionStreamStatus::Finished => break,
+        }
+    }
+    assert_eq!(tokens.concat(), "Hello!");
+    assert_eq!(server.requests()[0].json()["stream"], true);
+    assert_eq!(a.cache.len(), 3);
+    assert_eq!(a.cache.as_ref()[2].content, "Hello!");
+    assert_eq!(a.usage().total(), TokenUsage::new(26, 3));
+}
+
+#[tokio::test]
+async fn ollama_embedding_works() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({"embedding": [0.5, -1.0, 0.25]}),
+    )])
+    .await;
+    let model = EmbeddingModel::new(
+        OllamaEmbeddingModel::with_host(&server.url, "nomic-embed-text"),
+        "",
+    );
+    let embedding = model.get_embedding("some text").await.unwrap();
+    assert_eq!(embedding, vec![0.5, -1.0, 0.25]);
+
+    let req = &server.requests()[0];
+    assert_eq!(req.path, "/api/embeddings");
+    assert_eq!(
+        req.json(),
+        json!({"model": "nomic-embed-text", "prompt": "some text"})
+    );
+}
+
+fn fast_retries(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy::default()
+        .with_max_attempts(max_attempts)
+        .with_backoff(Duration::from_millis(1), Duration::from_millis(5))
+}
+
+fn compatible_success() -> Value {
+    json!({
+        "id": "chatcmpl-local",
+        "object": "chat.completion",
+        "created": 1677858242,
+        "model": "mistral-7b",
+        "usage": {"prompt_tokens": 13, "completion_tokens": 7, "total_tokens": 20},
+        "choices": [{
+            "message": {"role": "assistant", "content": "finally"},
+            "finish_reason": "stop",
+            "index": 0
+        }]
+    })
+}
+
+#[tokio::test]
+async fn failed_requests_are_retried() {
+    init_test();
+    let rate_limited = json!({"error": {"code": "rate_limit_exceeded", "message": "slow down"}});
+    let overloaded = json!({
+        "type": "error",
+        "error": {"type": "overloaded_error", "message": "Overloaded"}
+    });
+    let server = StubServer::start(vec![
+        StubResponse::json(429, rate_limited).with_header("retry-after", "0"),
+        StubResponse::json(529, overloaded),
+        StubResponse::json(200, compatible_success()),
+    ])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(fast_retries(3));
+    let mut a = Agent::new(Some("system"), llm);
+
+    assert_eq!(a.io_completion().await.unwrap(), "finally");
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn retries_give_up_after_max_attempts() {
+    init_test();
+    let unavailable = json!({"error": {"code": "server_error", "message": "unavailable"}});
+    let server = StubServer::start(vec![StubResponse::json(503, unavailable)]).await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(fast_retries(2));
+    let mut a = Agent::new(Some("system"), llm);
+
+    assert!(a.io_completion().await.is_err());
+    assert_eq!(server.requests().len(), 2);
+
+    // Client errors other than rate limits are never retried
+    let bad_request = json!({"error": {"code": "invalid_request", "message": "bad"}});
+    let server = StubServer::start(vec![StubResponse::json(400, bad_request)]).await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(fast_retries(3));
+    let mut a = Agent::new(Some("system"), llm);
+
+    assert!(a.io_completion().await.is_err());
+    assert_eq!(server.requests().len(), 1);
+
+    // Neither is running out of quota
+    let no_quota = json!({"error": {"code": "insufficient_quota", "type": "insufficient_quota", "message": "You exceeded your current quota"}});
+    let server = StubServer::start(vec![StubResponse::json(429, no_quota)]).await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(fast_retries(3));
+    let mut a = Agent::new(Some("system"), llm);
+
+    let err = a.io_completion().await.unwrap_err();
+    assert!(matches!(
+        err.completion_error(),
+        Some(CompletionError::RateLimited(_))
+    ));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn requested_waits_are_capped_at_max_backoff() {
+    init_test();
+    let rate_limited = json!({"error": {"code": "rate_limit_exceeded", "message": "slow down"}});
+    let server = StubServer::start(vec![
+        StubResponse::json(429, rate_limited).with_header("retry-after", "3600"),
+        StubResponse::json(200, compatible_success()),
+    ])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(fast_retries(2));
+    let mut a = Agent::new(Some("system"), llm);
+
+    let answer = tokio::time::timeout(Duration::from_secs(5), a.io_completion())
+        .await
+        .expect("the hour long retry-after should be capped");
+    assert_eq!(answer.unwrap(), "finally");
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn retries_are_rate_limited() {
+    init_test();
+    let unavailable = json!({"error": {"code": "server_error", "message": "unavailable"}});
+    let server = StubServer::start(vec![
+        StubResponse::json(503, unavailable),
+        StubResponse::json(200, compatible_success()),
+        StubResponse::json(200, compatible_success()),
+    ])
+    .await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(fast_retries(2))
+        .with_rate_limit(RateLimit::requests_per_minute(2));
+    let mut a = Agent::new(Some("system"), llm);
+
+    assert_eq!(a.io_completion().await.unwrap(), "finally");
+    assert_eq!(server.requests().len(), 2);
+
+    // The retry used up the second request of the minute
+    let next = tokio::time::timeout(Duration::from_millis(500), a.io_completion()).await;
+    assert!(next.is_err());
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn provider_errors_are_typed() {
+    init_test();
+    let too_long = json!({
+        "error": {
+            "message": "This model's maximum context length is 8192 tokens",
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": "context_length_exceeded"
+        }
+    });
+    let server = StubServer::start(vec![StubResponse::json(400, too_long.clone())]).await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(RetryPolicy::none());
+    let mut a = Agent::new(Some("system"), llm);
+
+    let err = a.io_completion().await.unwrap_err();
+    assert!(!err.is_retryable());
+    let Some(CompletionError::ContextLengthExceeded(provider)) = err.completion_error() else {
+        panic!("expected context length error, got {:?}", err);
+    };
+    assert_eq!(provider.status, Some(400));
+    assert_eq!(provider.code.as_deref(), Some("context_length_exceeded"));
+    assert_eq!(
+        serde_json::from_str::<Value>(&provider.body).unwrap(),
+        too_long
+    );
+
+    let rate_limited = json!({"error": {"code": "rate_limit_exceeded", "message": "slow down"}});
+    let server = StubServer::start(vec![StubResponse::json(429, rate_limited)]).await;
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", server.url), "mistral-7b");
+    let llm = CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(RetryPolicy::none());
+    let mut a = Agent::new(Some("system"), llm);
+
+    let err = a.stream_completion().await.unwrap_err();
+    assert!(err.is_retryable());
+    assert!(matches!(
+        err.completion_error(),
+        Some(CompletionError::RateLimited(_))
+    ));
+
+    // Errors sent in a successful response keep the raw body too
+    let server = StubServer::start(vec![StubResponse::json(200, too_long.clone())]).await;
+    let mut a = Agent::new(Some("system"), compatible_model(&server.url));
+
+    let err = a.io_completion().await.unwrap_err();
+    let provider = err.completion_error().unwrap().provider_error().unwrap();
+    assert_eq!(provider.status, None);
+    assert_eq!(provider.code.as_deref(), Some("context_length_exceeded"));
+    assert_eq!(
+        serde_json::from_str::<Value>(&provider.body).unwrap(),
+        too_long
+    );
+}
+
+fn compatible_model(url: &str) -> CompletionModel {
+    let model = OpenAiCompatibleModel::new(&format!("{}/v1", url), "mistral-7b");
+    CompletionModel::new(model, ModelParameters::default(), "")
+        .with_retry_policy(RetryPolicy::none())
+}
+
+#[tokio::test]
+async fn fallback_answers_when_primary_fails() {
+    init_test();
+    let overloaded = json!({
+        "type": "error",
+        "error": {"type": "overloaded_error", "message": "Overloaded"}
+    });
+    let primary = StubServer::start(vec![StubResponse::json(529, overloaded)]).await;
+    let fallback = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let llm = compatible_model(&primary.url).with_fallback(compatible_model(&fallback.url));
+    let mut a = Agent::new(Some("system"), llm);
+
+    assert_eq!(a.io_completion().await.unwrap(), "finally");
+    let answered_by = a.answered_by().unwrap();
+    assert_eq!(answered_by.index, 1);
+    assert!(answered_by.is_fallback());
+    assert_eq!(answered_by.provider, "OpenAi compatible");
+    assert_eq!(primary.requests().len(), 1);
+    assert_eq!(fallback.requests().len(), 1);
+
+    // Errors that would fail the same way anywhere are not passed on
+    let bad_key = json!({"error": {"code": "invalid_api_key", "message": "bad key"}});
+    let primary = StubServer::start(vec![StubResponse::json(401, bad_key)]).await;
+    let fallback = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let llm = compatible_model(&primary.url).with_fallback(compatible_model(&fallback.url));
+    let mut a = Agent::new(Some("system"), llm);
+
+    let err = a.io_completion().await.unwrap_err();
+    assert!(matches!(
+        err.completion_error(),
+        Some(CompletionError::Authentication(_))
+    ));
+    assert!(fallback.requests().is_empty());
+}
+
+#[tokio::test]
+async fn stream_falls_back_before_first_token() {
+    init_test();
+    // Accepts connections but never answers
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_url = format!("http://{}", silent.local_addr().unwrap());
+    let ollama = StubServer::start(vec![StubResponse::ndjson(vec![
+        json!({"model": "llama3", "created_at": "2023-08-04T08:52:19.385406455-07:00", "message": {"role": "assistant", "content": "Hi"}, "done": false}),
+        json!({"model": "llama3", "created_at": "2023-08-04T08:52:19.385406455-07:00", "message": {"role": "assistant", "content": ""}, "done": true}),
+    ])])
+    .await;
+    let fallback = CompletionModel::new(
+        OllamaCompletionModel::with_host(&ollama.url, "llama3"),
+        ModelParameters::default(),
+        "",
+    );
+    let llm = compatible_model(&silent_url)
+        .with_timeout(Duration::from_millis(200))
+        .with_fallback(fallback);
+    let mut a = Agent::new(Some("system"), llm);
+
+    let mut response = a.stream_completion().await.unwrap();
+    assert_eq!(a.answered_by().unwrap().provider, "Ollama");
+    while let Ok(Some(status)) = response.receive(&mut a).await {
+        if let CompletionStreamStatus::Finished = status {
+            break;
+        }
+    }
+    assert_eq!(a.cache.as_ref()[1].content, "Hi");
+
+    // An error sent in place of the first chunk is returned before any token is received
+    let errored = StubServer::start(vec![StubResponse::ndjson(vec![
+        json!({"error": "model 'llama9' not found"}),
+    ])])
+    .await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::with_host(&errored.url, "llama9"),
+        ModelParameters::default(),
+        "",
+    );
+    let mut a = Agent::new(Some("system"), llm);
+    assert!(a.stream_completion().await.is_err());
+}
+
+#[tokio::test]
+async fn raced_models_answer_with_the_fastest() {
+    init_test();
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_url = format!("http://{}", silent.local_addr().unwrap());
+    let fast = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let llm = compatible_model(&silent_url)
+        .with_fallback(compatible_model(&fast.url))
+        .with_fallback_mode(FallbackMode::race());
+    let mut a = Agent::new(Some("system"), llm);
+
+    let answer = tokio::time::timeout(Duration::from_secs(5), a.io_completion())
+        .await
+        .expect("race waited on the silent model");
+    assert_eq!(answer.unwrap(), "finally");
+    assert_eq!(a.answered_by().unwrap().index, 1);
+
+    // Hedges are only sent once the delay passes without an answer
+    let primary = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let hedge = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let llm = compatible_model(&primary.url)
+        .with_fallback(compatible_model(&hedge.url))
+        .with_fallback_mode(FallbackMode::Hedged(Duration::from_secs(5)));
+    let mut a = Agent::new(Some("system"), llm);
+
+    assert_eq!(a.io_completion().await.unwrap(), "finally");
+    assert_eq!(a.answered_by().unwrap().index, 0);
+    assert!(hedge.requests().is_empty());
+
+    // A failure starts the next model right away
+    let failing = StubServer::start(vec![StubResponse::json(
+        400,
+        json!({"error": {"code": "invalid_request", "message": "bad"}}),
+    )])
+    .await;
+    let llm = compatible_model(&failing.url)
+        .with_fallback(compatible_model(&hedge.url))
+        .with_fallback_mode(FallbackMode::Hedged(Duration::from_secs(5)));
+    let mut a = Agent::new(Some("system"), llm);
+
+    let answer = tokio::time::timeout(Duration::from_secs(2), a.io_completion())
+        .await
+        .expect("hedge waited out the delay after a failure");
+    assert_eq!(answer.unwrap(), "finally");
+    assert_eq!(a.answered_by().unwrap().index, 1);
+}
+
+#[tokio::test]
+async fn identical_requests_are_served_from_cache() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let llm = compatible_model(&server.url).with_cache(ResponseCache::memory(10));
+    let mut a = Agent::new(Some("system"), llm);
+
+    assert_eq!(a.io_completion().await.unwrap(), "finally");
+    let cached = a.detailed_io_completion().await.unwrap();
+    assert_eq!(cached.content(), Some("finally"));
+    assert_eq!(cached.usage, None);
+    assert_eq!(server.requests().len(), 1);
+    assert_eq!(a.usage().total(), TokenUsage::new(13, 7));
+
+    assert_eq!(a.bypass_cache().io_completion().await.unwrap(), "finally");
+    assert_eq!(server.requests().len(), 2);
+    a.io_completion().await.unwrap();
+    assert_eq!(server.requests().len(), 2);
+
+    // A different conversation is a different request
+    a.cache.push(Message::new_user("something else"));
+    a.io_completion().await.unwrap();
+    assert_eq!(server.requests().len(), 3);
+
+    let server = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({"embedding": [0.5, -1.0]}),
+    )])
+    .await;
+    let model = EmbeddingModel::new(
+        OllamaEmbeddingModel::with_host(&server.url, "nomic-embed-text"),
+        "",
+    )
+    .with_cache(ResponseCache::memory(10));
+    model.get_embedding("some text").await.unwrap();
+    assert_eq!(
+        model.get_embedding("some text").await.unwrap(),
+        vec![0.5, -1.0]
+    );
+    assert_eq!(server.requests().len(), 1);
+    model.get_embedding_uncached("some text").await.unwrap();
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn semantic_cache_answers_similar_questions() {
+    init_test();
+    let completions = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let embeddings = StubServer::start(vec![
+        StubResponse::json(200, json!({"embedding": [1.0, 0.0]})),
+        StubResponse::json(200, json!({"embedding": [0.98, 0.1]})),
+        StubResponse::json(200, json!({"embedding": [0.0, 1.0]})),
+    ])
+    .await;
+    let embedder = EmbeddingModel::new(
+        OllamaEmbeddingModel::with_host(&embeddings.url, "nomic-embed-text"),
+        "",
+    );
+    let semantic_cache = SemanticCache::new(embedder).with_threshold(0.9);
+
+    let mut a = Agent::new(Some("system"), compatible_model(&completions.url))
+        .with_semantic_cache(semantic_cache.clone());
+    a.cache
+        .push(Message::new_user("What is the capital of France?"));
+    assert_eq!(a.io_completion().await.unwrap(), "finally");
+
+    // Clones share entries, so another agent benefits too
+    let mut b = Agent::new(Some("system"), compatible_model(&completions.url))
+        .with_semantic_cache(semantic_cache.clone());
+    b.cache.push(Message::new_user("what's france's capital"));
+    let cached = b.detailed_io_completion().await.unwrap();
+    assert_eq!(cached.content(), Some("finally"));
+    assert_eq!(cached.usage, None);
+    assert_eq!(completions.requests().len(), 1);
+
+    b.cache.push(Message::new_user("Tell me a joke"));
+    b.io_completion().await.unwrap();
+    assert_eq!(completions.requests().len(), 2);
+
+    let stats = semantic_cache.stats();
+    assert_eq!(stats, CacheStats { hits: 1, misses: 2 });
+    assert_eq!(semantic_cache.len(), 2);
+
+    // The same question under another system prompt is not answered from the cache
+    let mut c = Agent::new(
+        Some("Answer like a pirate"),
+        compatible_model(&completions.url),
+    )
+    .with_semantic_cache(semantic_cache.clone());
+    c.cache.push(Message::new_user("Tell me a joke"));
+    c.io_completion().await.unwrap();
+    assert_eq!(completions.requests().len(), 3);
+    assert_eq!(semantic_cache.stats(), CacheStats { hits: 1, misses: 3 });
+}
+
+#[tokio::test]
+async fn embedding_requests_are_retried() {
+    init_test();
+    let server = StubServer::start(vec![
+        StubResponse::json(503, json!({"error": "loading model"})),
+        StubResponse::json(200, json!({"embedding": [0.5, -1.0]})),
+    ])
+    .await;
+    let model = EmbeddingModel::new(
+        OllamaEmbeddingModel::with_host(&server.url, "nomic-embed-text"),
+        "",
+    )
+    .with_retry_policy(fast_retries(3));
+
+    assert_eq!(
+        model.get_embedding("some text").await.unwrap(),
+        vec![0.5, -1.0]
+    );
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn api_keys_are_never_serialized() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let fallback = compatible_model(&server.url).with_api_key("sk-fallback-key");
+    let model = compatible_model("http://127.0.0.1:9")
+        .with_api_key("sk-primary-key")
+        .with_fallback(fallback);
+    let agent = Agent::new(Some("system"), model);
+
+    let json = serde_json::to_string(&agent).unwrap();
+    assert!(!json.contains("sk-primary-key") && !json.contains("sk-fallback-key"));
+    assert!(!format!("{:?}", agent).contains("sk-primary-key"));
+
+    let mut agent: Agent = serde_json::from_str(&json).unwrap();
+    agent.supply_api_keys(|_| Some("sk-restored-key".into()));
+    agent.cache.push(Message::new_user("hello"));
+
+    assert_eq!(agent.io_completion().await.unwrap(), "finally");
+    assert!(agent.answered_by().unwrap().is_fallback());
+    assert_eq!(
+        server.requests()[0].headers["authorization"],
+        "Bearer sk-restored-key"
+    );
+}
+
+#[tokio::test]
+async fn credentials_are_resolved_per_request() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let model =
+        compatible_model(&server.url).with_credentials(RoundRobinKeys::new(["sk-one", "sk-two"]));
+    let mut agent = Agent::new(Some("system"), model);
+    agent.cache.push(Message::new_user("hello"));
+    for _ in 0..3 {
+        agent.io_completion().await.unwrap();
+    }
+
+    let var = "ESPIONOX_TEST_ROTATED_KEY";
+    agent.completion_model = compatible_model(&server.url).with_credentials(EnvVarKey::new(var));
+    let err = agent.io_completion().await.unwrap_err();
+    assert!(matches!(
+        err.completion_error(),
+        Some(CompletionError::Credentials(_))
+    ));
+    std::env::set_var(var, "sk-old");
+    agent.io_completion().await.unwrap();
+    std::env::set_var(var, "sk-new\n");
+    agent.io_completion().await.unwrap();
+
+    // Keys that cannot be sent in a header fail the completion instead of panicking
+    agent.completion_model = compatible_model(&server.url)
+        .with_credentials(KeyCallback::new(|| Ok("sk-bad\r\nx-injected: 1".into())));
+    let err = agent.io_completion().await.unwrap_err();
+    assert!(matches!(
+        err.completion_error(),
+        Some(CompletionError::Credentials(CredentialError::InvalidKey))
+    ));
+
+    let keys: Vec<String> = server
+        .requests()
+        .iter()
+        .map(|r| r.headers["authorization"].clone())
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            "Bearer sk-one",
+            "Bearer sk-two",
+            "Bearer sk-one",
+            "Bearer sk-old",
+            "Bearer sk-new"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn configured_client_is_used_and_reattached() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::json(200, compatible_success())]).await;
+    let client = TransportConfig::default()
+        .with_header(
+            HeaderName::from_static("openai-organization"),
+            HeaderValue::from_static("org-123"),
+        )
+        .build()
+        .unwrap();
+    let llm = compatible_model(&server.url)
+        .with_fallback(compatible_model(&server.url))
+        .with_client(client.clone());
+    let mut a = Agent::new(Some("system"), llm);
+    a.cache.push(Message::new_user("hello"));
+    a.io_completion().await.unwrap();
+
+    let mut restored: Agent = serde_json::from_str(&serde_json::to_string(&a).unwrap()).unwrap();
+    restored.io_completion().await.unwrap();
+    let embeddings = StubServer::start(vec![StubResponse::json(
+        200,
+        json!({"embedding": [1.0, 0.0]}),
+    )])
+    .await;
+    let embedder = EmbeddingModel::new(
+        OllamaEmbeddingModel::with_host(&embeddings.url, "nomic-embed-text"),
+        "",
+    );
+    restored.semantic_cache = Some(SemanticCache::new(embedder));
+    restored.set_client(client);
+    restored.io_completion().await.unwrap();
+    let embedding_request = &embeddings.requests()[0];
+    assert_eq!(
+        embedding_request.headers.get("openai-organization"),
+        Some(&"org-123".to_owned())
+    );
+
+    let orgs: Vec<Option<String>> = server
+        .requests()
+        .iter()
+        .map(|r| r.headers.get("openai-organization").cloned())
+        .collect();
+    assert_eq!(
+        orgs,
+        [Some("org-123".to_owned()), None, Some("org-123".to_owned())]
+    );
+
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TransportConfig::default()
+        .with_connect_timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    // Connecting succeeds, reads are limited by the model's timeout
+    let llm = compatible_model(&format!("http://{}", silent.local_addr().unwrap()))
+        .with_client(client)
+        .with_timeout(Duration::from_millis(100));
+    let mut a = Agent::new(Some("system"), llm);
+    let err = a.io_completion().await.unwrap_err();
+    assert!(matches!(
+        err.completion_error(),
+        Some(CompletionError::Timeout(_))
+    ));
+}
+
+#[tokio::test]
+async fn server_sent_events_are_streamed() {
+    init_test();
+    let error = "data: {\"error\": {\"message\": \"Rate limit reached\", \"type\": \"requests\", \"code\": \"rate_limit_exceeded\"}}\n\n";
+    let primary = StubServer::start(vec![StubResponse::sse(error)]).await;
+    let server = StubServer::start(vec![StubResponse::sse(include_str!(
+        "../transcripts/openai_stream.sse"
+    ))])
+    .await;
+    let llm = compatible_model(&primary.url).with_fallback(compatible_model(&server.url));
+    let mut a = Agent::new(Some("system"), llm);
+    a.cache.push(Message::new_user("hello"));
+
+    let mut response = a.stream_completion().await.unwrap();
+    assert_eq!(a.answered_by().unwrap().index, 1);
+    let mut tokens = String::new();
+    while let Some(status) = response.receive(&mut a).await.unwrap() {
+        match status {
+            CompletionStreamStatus::Working(token) => tokens.push_str(&token),
+            CompletionStreamStatus::Finished => break,
+        }
+    }
+    assert_eq!(tokens, "Hello");
+    assert_eq!(a.cache.as_ref()[2].content, "Hello");
+    assert_eq!(a.usage().last(), Some(TokenUsage::new(19, 2)));
+    assert_eq!(server.requests()[0].json()["stream"], true);
+}
+
+#[tokio::test]
+async fn streamed_completions_are_streams() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::sse(include_str!(
+        "../transcripts/openai_stream.sse"
+    ))])
+    .await;
+    let mut a = Agent::new(Some("system"), compatible_model(&server.url));
+    a.cache.push(Message::new_user("hello"));
+
+    let mut response = a.stream_completion().await.unwrap();
+    let events: Vec<StreamEvent> = (&mut response).try_collect().await.unwrap();
+    assert_eq!(
+        events,
+        [
+            StreamEvent::TextDelta("Hello".to_owned()),
+            StreamEvent::Stop("stop".to_owned()),
+            StreamEvent::Usage(TokenUsage::new(19, 2))
+        ]
+    );
+    assert_eq!(a.cache.len(), 2);
+
+    assert!(response.commit(&mut a));
+    assert!(!response.commit(&mut a));
+    assert_eq!(a.cache.len(), 3);
+    assert_eq!(a.cache.as_ref()[2].content, "Hello");
+    assert_eq!(a.usage().last(), Some(TokenUsage::new(19, 2)));
+}
+
+#[tokio::test]
+async fn function_arguments_are_streamed() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::sse(include_str!(
+        "../transcripts/openai_tool_stream.sse"
+    ))])
+    .await;
+    let mut a = Agent::new(Some("system"), compatible_model(&server.url));
+    a.cache
+        .push(Message::new_user("What's the weather in Paris?"));
+    let function = Function::try_from("get_weather(location!: string)").unwrap();
+
+    let mut response = a.stream_function_completion(function).await.unwrap();
+    let events: Vec<StreamEvent> = (&mut response).try_collect().await.unwrap();
+    let fragments: Vec<&str> = events
+        .iter()
+        .filter_map(|event| match event {
+            StreamEvent::ToolCallDelta { arguments, .. } => Some(arguments.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(fragments, ["", "{\"lo", "cation\":", " \"Paris\"}"]);
+    assert_eq!(
+        events.last(),
+        Some(&StreamEvent::ToolCall(ToolCall {
+            id: "call_DdmO9pD3xa9XTPNJ32zg2hcA".to_owned(),
+            name: "get_weather".to_owned(),
+            arguments: json!({"location": "Paris"}),
+        }))
+    );
+
+    assert!(response.commit(&mut a));
+    assert_eq!(a.cache.len(), 2);
+    assert_eq!(a.usage().last(), Some(TokenUsage::new(64, 15)));
+
+    let body = server.requests()[0].json();
+    assert_eq!(body["stream"], true);
+    assert_eq!(body["stream_options"]["include_usage"], true);
+    assert_eq!(body["tool_choice"]["function"]["name"], "get_weather");
+}
+
+#[tokio::test]
+async fn streamed_arguments_are_parsed_as_they_arrive() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::sse(include_str!(
+        "../transcripts/openai_tool_stream.sse"
+    ))])
+    .await;
+    let mut a = Agent::new(Some("system"), compatible_model(&server.url));
+    a.cache
+        .push(Message::new_user("What's the weather in Paris?"));
+    let function = Function::try_from("get_weather(location!: string)").unwrap();
+
+    let mut response = a.stream_function_completion(function).await.unwrap();
+    let mut partial = PartialJsonStream::new(&mut response);
+    let values: Vec<Value> = (&mut partial).try_collect().await.unwrap();
+    assert_eq!(values, [json!({}), json!({"location": "Paris"})]);
+    assert_eq!(partial.value(), Some(&json!({"location": "Paris"})));
+
+    assert!(response.commit(&mut a));
+    assert_eq!(a.usage().last(), Some(TokenUsage::new(64, 15)));
+}
+
+#[tokio::test]
+async fn only_the_first_streamed_tool_call_is_parsed() {
+    init_test();
+    let server = StubServer::start(vec![StubResponse::sse(include_str!(
+        "../transcripts/openai_parallel_tool_stream.sse"
+    ))])
+    .await;
+    let mut a = Agent::new(Some("system"), compatible_model(&server.url));
+    a.cache
+        .push(Message::new_user("What's the weather in Paris and Tokyo?"));
+    let function = Function::try_from("get_weather(location!: string)").unwrap();
+
+    let response = a.stream_function_completion(function).await.unwrap();
+    let mut partial = response.partial_json();
+    let values: Vec<Value> = (&mut partial).try_collect().await.unwrap();
+    assert_eq!(values, [json!({}), json!({"location": "Paris"})]);
+    assert_eq!(partial.value(), Some(&json!({"location": "Paris"})));
+}
+
+#[test]
+fn percent_temperatures_are_still_read() {
+    let saved = json!({
+        "total_token_count": 0,
+        "temperature": 70,
+        "frequency_penalty": null,
+        "max_tokens": null,
+        "n": 1,
+        "presence_penalty": null
+    });
+    let params: ModelParameters = serde_json::from_value(saved).unwrap();
+    assert_eq!(params.temperature, Some(0.7));
+
+    for (temperature, expected) in [(json!(1), 1.0), (json!(0.3), 0.3), (json!(1.5), 1.5)] {
+        let mut json = serde_json::to_value(ModelParameters::default()).unwrap();
+        json["temperature"] = temperature;
+        let params: ModelParameters = serde_json::from_value(json).unwrap();
+        assert_eq!(params.temperature, Some(expected));
+    }
+    let params: ModelParameters = serde_json::from_value(json!({})).unwrap();
+    assert_eq!(params.temperature, None);
+}
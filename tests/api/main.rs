@@ -7,5 +7,7 @@ pub mod functions;
 pub mod helpers;
 pub mod language_models;
 pub mod memory;
+pub mod stub_server;
 
 pub use helpers::*;
+pub use stub_server::*;